@@ -50,6 +50,11 @@ public class ChatScreen extends JFrame implements ActionListener, KeyListener
 	public static final int PORT = 8000;
 	public String jsonMessage;
 
+	// When TYPING was last sent, so it goes out at most every two seconds
+	private long lastTypingSent = 0;
+	// Puts the title back once someone's typing indicator expires
+	private Timer typingTimer;
+
 	//Colors for GUI styling
 	Color purple = new Color(151,153,186);
 	Color lightpink = new Color(249,225,224);
@@ -264,7 +269,7 @@ public class ChatScreen extends JFrame implements ActionListener, KeyListener
 			// Split the input into recipients and message
 			SplitMessage splitMessage = splitMessage(message);
 
-			if (splitMessage != null) {
+			if (!splitMessage.getMessage().isEmpty()) {
 				String header = splitMessage.getHeader();
 				String newMessage = splitMessage.getMessage();
 				String username = getUsername();
@@ -321,25 +326,17 @@ public class ChatScreen extends JFrame implements ActionListener, KeyListener
 		if (!hasAtSymbol) {
 			header.add("@all");
 			message = input.trim(); // Entire input is treated as the message
-		} else {
-			try{
-				// Reconstruct the message part
-				int startIndex = input.indexOf(parts[header.size()]);
-				message = input.substring(startIndex).trim();
-			}
-			catch (ArrayIndexOutOfBoundsException e){
-				JOptionPane.showMessageDialog(this, "Invalid format. Message cannot be blank.", "Message Not Found", JOptionPane.ERROR_MESSAGE);
-			}
-
+		} else if (parts.length > header.size()) {
+			// Reconstruct the message part
+			int startIndex = input.indexOf(parts[header.size()]);
+			message = input.substring(startIndex).trim();
 		}
 
 		// Convert the header list to a single string of recipients
 		String headerString = String.join(" ", header); // Joins the recipients with a space
 
-		if (message.isEmpty()) {
-			return null; // Return null if message is empty
-		}
-
+		// The message is empty when only recipients were typed; sending checks for that,
+		// while the typing indicator only needs the header
 		return new SplitMessage(headerString, message);
 	}
 
@@ -525,6 +522,55 @@ public class ChatScreen extends JFrame implements ActionListener, KeyListener
 		//not implementing it currently
 	}
 
+	// Answers a server heartbeat so the connection is not dropped as dead; called from the reader thread
+	public void sendPong(String token) {
+		try {
+			synchronized (toServer) {
+				toServer.write(token.isEmpty() ? "PONG\n" : "PONG " + token + "\n");
+				toServer.flush();
+			}
+		} catch (IOException e) {
+			System.err.println("Failed to answer PING: " + e.getMessage());
+		}
+	}
+
+	// Typing indicators are best effort, so a failure is only logged
+	private void sendTyping(String header) {
+		try {
+			synchronized (toServer) {
+				toServer.write("TYPING " + header + "\n");
+				toServer.flush();
+			}
+		} catch (IOException e) {
+			System.err.println("Failed to send typing indicator: " + e.getMessage());
+		}
+	}
+
+	// Shows who is typing in the title bar until the indicator expires; called from the reader thread
+	public void showTyping(String typist, int seconds) {
+		SwingUtilities.invokeLater(() -> {
+			setTitle("The Cool Chatroom - " + typist + " is typing...");
+			if (typingTimer != null) {
+				typingTimer.stop();
+			}
+			typingTimer = new Timer(seconds * 1000, evt -> setTitle("The Cool Chatroom"));
+			typingTimer.setRepeats(false);
+			typingTimer.start();
+		});
+	}
+
+	// Tells the server a relayed message has been shown; called from the reader thread
+	public void sendAck(long id) {
+		try {
+			synchronized (toServer) {
+				toServer.write("ACK " + id + " READ\n");
+				toServer.flush();
+			}
+		} catch (IOException e) {
+			System.err.println("Failed to acknowledge message " + id + ": " + e.getMessage());
+		}
+	}
+
 	// Custom DocumentFilter to limit input length
 	static class LengthFilter extends DocumentFilter {
 		private final int maxLength;
@@ -572,8 +618,22 @@ public class ChatScreen extends JFrame implements ActionListener, KeyListener
 	/** Not implemented */
 	public void keyReleased(KeyEvent e) { }
 
-	/** Not implemented */
-	public void keyTyped(KeyEvent e) {  }
+	/**
+	 * Lets the recipients of the message being written
+	 * know the user is typing.
+	 */
+	public void keyTyped(KeyEvent e) {
+		if (e.getKeyChar() == '\n' || toServer == null) {
+			return;
+		}
+		long now = System.currentTimeMillis();
+		if (now - lastTypingSent < 2000) {
+			return;
+		}
+		lastTypingSent = now;
+		// The same header SEND will use, so the indicator reaches the same people
+		sendTyping(splitMessage(sendText.getText()).getHeader());
+	}
 
 	public static void main(String[] args) {
 		if (args.length != 1) {
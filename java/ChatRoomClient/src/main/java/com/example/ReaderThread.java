@@ -74,6 +74,11 @@ public class ReaderThread implements Runnable
 			} else {
 				screen.displayMessage(sender + " [Private]: " + clientMessage + "\n\n\n" + timestamp);
 			}
+
+			// Messages relayed by the server carry an ID; tell the sender this one has been shown
+			if (jsonNode.has("id")) {
+				screen.sendAck(jsonNode.get("id").asLong());
+			}
 		} catch (Exception e) {
 			e.printStackTrace();
 			System.err.println("Failed to parse JSON payload: " + message);
@@ -119,7 +124,8 @@ public class ReaderThread implements Runnable
 							e.printStackTrace();
 						}
 						}
-					else if (payload.startsWith("SENT")) {
+					// QUEUED means an offline recipient gets it when they next log in; either way it was sent
+					else if (payload.startsWith("SENT") || payload.startsWith("QUEUED")) {
 						processJsonMessage(screen.jsonMessage);
 					}
 					break;
@@ -131,6 +137,61 @@ public class ReaderThread implements Runnable
 						JOptionPane.showMessageDialog(screen, "Invalid message format. Message failed to be sent to the server.", "Message Failed", JOptionPane.ERROR_MESSAGE);
 					}
 					break;
+				case "401":
+					if (payload.startsWith("LOGIN REQUIRED")) {
+						JOptionPane.showMessageDialog(screen, "This server only accepts registered accounts, and this client can only JOIN as a guest.\n"
+								+ "Use the Rust client to REGISTER and LOGIN, or ask the operator to start the server with CHAT_ALLOW_GUESTS=1.",
+								"Login Required", JOptionPane.ERROR_MESSAGE);
+					} else if (payload.startsWith("NOT JOINED")) {
+						JOptionPane.showMessageDialog(screen, "You have not joined the chatroom yet.", "Not Joined", JOptionPane.ERROR_MESSAGE);
+					}
+					break;
+				case "403":
+					if (payload.startsWith("MUTED")) {
+						JOptionPane.showMessageDialog(screen, "You are muted and cannot send messages right now.", "Muted", JOptionPane.WARNING_MESSAGE);
+					} else if (payload.startsWith("BANNED")) {
+						JOptionPane.showMessageDialog(screen, "You are banned from this server.", "Banned", JOptionPane.ERROR_MESSAGE);
+					}
+					break;
+				case "SHUTDOWN":
+					String notice = payload.isEmpty() ? "The server is shutting down." : "The server is shutting down: " + payload;
+					JOptionPane.showMessageDialog(screen, notice, "Server Shutting Down", JOptionPane.INFORMATION_MESSAGE);
+					break;
+				case "KICKED":
+					String reason = payload.isEmpty() ? "You were kicked by an admin." : "You were kicked by an admin: " + payload;
+					JOptionPane.showMessageDialog(screen, reason, "Kicked", JOptionPane.INFORMATION_MESSAGE);
+					break;
+				case "PING":
+					screen.sendPong(payload.trim());
+					break;
+				case "EDITED":
+					try {
+						JsonNode edit = new ObjectMapper().readTree(payload);
+						screen.displayMessage("Message " + edit.get("id").asLong() + " was edited: " + edit.get("message").asText() + "\n");
+					} catch (IOException e) {
+						System.err.println("Failed to parse edit JSON: " + payload);
+					}
+					break;
+				case "DELETED":
+					screen.displayMessage("Message " + payload.trim() + " was deleted\n");
+					break;
+				case "TYPING":
+					try {
+						JsonNode typing = new ObjectMapper().readTree(payload);
+						screen.showTyping(typing.get("username").asText(), typing.get("expires_in").asInt());
+					} catch (IOException e) {
+						System.err.println("Failed to parse typing JSON: " + payload);
+					}
+					break;
+				case "RECEIPT":
+					try {
+						JsonNode receipt = new ObjectMapper().readTree(payload);
+						String state = "READ".equals(receipt.get("state").asText()) ? "read" : "received";
+						screen.displayMessage(receipt.get("username").asText() + " has " + state + " message " + receipt.get("id").asLong() + "\n");
+					} catch (IOException e) {
+						System.err.println("Failed to parse receipt JSON: " + payload);
+					}
+					break;
 				case "500":
 					JOptionPane.showMessageDialog(screen, "Server error.", "Server Error", JOptionPane.ERROR_MESSAGE);
 
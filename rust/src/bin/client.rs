@@ -1,20 +1,66 @@
-use std::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
 use std::io::{self, Write, Read, stdin};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::collections::VecDeque;
-use serde_json::Value;
 use chrono::Utc;
 use std::time::Duration;
+use homework4::protocol::{ChatMessage, Command, PresenceKind, ReceiptState, Response, MAX_MESSAGE_LENGTH};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned};
+use tracing::{debug, error, info};
+use tracing_subscriber::EnvFilter;
+
+// Plain TCP or TLS, so both threads can share whichever one we connected with
+trait Transport: Read + Write + Send {}
+impl<T: Read + Write + Send> Transport for T {}
+
+type SharedStream = Arc<Mutex<Box<dyn Transport>>>;
 
 fn main() -> io::Result<()> {
-    let local_ip = local_ip_address::local_ip().expect("Could not get local IP");
-    //let local_ip = ""; //for running on others devices
-    let address = format!("{}:{}", local_ip, 8000);
+    // Diagnostics go to stderr and are quiet unless RUST_LOG asks for more, e.g. RUST_LOG=debug
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")))
+        .with_writer(io::stderr)
+        .with_target(false)
+        .init();
+
+    // CHAT_SERVER is the server's host:port; otherwise port 8000 on this machine's LAN address, or 127.0.0.1 if it
+    // has none, which is where the server listens by default
+    let address = std::env::var("CHAT_SERVER").unwrap_or_else(|_| {
+        let local_ip = local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(local_ip, 8000).to_string()
+    });
+    let host = address.rsplit_once(':').map_or(address.as_str(), |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
     let stream = TcpStream::connect(&address).expect("Could not connect to server");
 
-    let sender_stream = Arc::new(Mutex::new(stream.try_clone().expect("Failed to clone stream for sender")));
-    let receiver_stream = Arc::new(Mutex::new(stream));
+    let (sender_stream, receiver_stream): (SharedStream, SharedStream) = match tls_config()? {
+        Some(config) => {
+            // CHAT_TLS_SERVER_NAME must match a name in the server's certificate; defaults to the address we dialled
+            let server_name = std::env::var("CHAT_TLS_SERVER_NAME").unwrap_or(host);
+            let server_name = ServerName::try_from(server_name)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mut connection = ClientConnection::new(Arc::new(config), server_name).map_err(io::Error::other)?;
+            let mut stream = stream;
+            // Handshake up front so a bad certificate is reported before any command is typed
+            while connection.is_handshaking() {
+                connection.complete_io(&mut stream)?;
+            }
+            // A TLS stream cannot be cloned, so the receiver polls and lets go of the lock between reads
+            stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+            info!("TLS established");
+            let shared: SharedStream = Arc::new(Mutex::new(Box::new(StreamOwned::new(connection, stream))));
+            (Arc::clone(&shared), shared)
+        }
+        None => (
+            Arc::new(Mutex::new(Box::new(stream.try_clone().expect("Failed to clone stream for sender")))),
+            Arc::new(Mutex::new(Box::new(stream))),
+        ),
+    };
 
     println!("Connected to server at {}", address);
 
@@ -33,61 +79,47 @@ fn main() -> io::Result<()> {
                 stdin().read_line(&mut input).expect("Failed to read input");
 
                 let message = input.trim();
-                println!("[CLIENT] Message is: '{}'", message);
                 if message.is_empty() {
-                    println!("[CLIENT] Empty message. Skipping writing out to server.");
                     continue;
                 }
 
-                println!("Attempting to lock stream_sender in thread: {:?}", std::thread::current().id());
                 let mut stream = match sender_stream.lock() {
-                    Ok(stream) => {
-                        println!("Lock acquired by thread: {:?}", std::thread::current().id());
-                        stream
-                    }
+                    Ok(stream) => stream,
                     Err(e) => {
-                        eprintln!("Failed to lock stream in thread {:?}: {}", std::thread::current().id(), e);
+                        error!("Failed to lock stream: {}", e);
                         break;
                     }
                 };
-                println!("stream was locked");
 
-                if message.starts_with("SEND") {
-                    println!("[CLIENT] Processing SEND message.");
-                    let json_payload = &message["SEND".len()..].trim();
-
-                    if json_payload.len() < 1 || json_payload.len() > 500 {
-                        println!("[CLIENT ERROR] Message length must be between 1 and 500 characters.");
+                let command = match Command::parse(message) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        println!("Invalid command: {}", e);
                         continue;
                     }
+                };
 
-                    if let Some(processed_json) = process_send_message(json_payload) {
-                        println!("[CLIENT] Sending JSON message: {}", processed_json);
-                        if let Err(e) = stream.write_all(format!("SEND {}\n", processed_json).as_bytes()) {
-                            eprintln!("[CLIENT ERROR] Failed to send message: {}", e);
-                            break;
-                        }
-                        println!("[CLIENT] Message successfully sent to server.");
-
-                        if let Ok(parsed_json) = serde_json::from_str::<Value>(&processed_json) {
-                            if let Some(message_content) = parsed_json["message"].as_str() {
-                                println!("Message from you: {}", message_content);
-                            } else {
-                                println!("[CLIENT] JSON sent, but no 'message' field found.");
-                            }
-                        } else {
-                            println!("[CLIENT] Sent raw message: {}", message);
+                let command = match command {
+                    Command::Send(chat_message) => {
+                        let content_length = chat_message.message.trim().chars().count();
+                        if !(1..=MAX_MESSAGE_LENGTH).contains(&content_length) {
+                            println!("Message length must be between 1 and {} characters.", MAX_MESSAGE_LENGTH);
+                            continue;
                         }
-                    } else {
-                        println!("[CLIENT ERROR] Invalid JSON for SEND message.");
+                        Command::Send(process_send_message(chat_message))
                     }
-                } else {
-                    println!("[CLIENT] Sending raw message: {}", message);
-                    if let Err(e) = stream.write_all(format!("{}\n", message).as_bytes()) {
-                        eprintln!("[CLIENT ERROR] Failed to send raw message: {}", e);
-                        break;
-                    }
-                    println!("[CLIENT] Message successfully sent to server.");
+                    command => command,
+                };
+
+                // Debug formatting redacts REGISTER and LOGIN passwords, which the encoded line would not
+                debug!(?command, "Sending command");
+                if let Err(e) = stream.write_all(command.encode().as_bytes()) {
+                    error!("Failed to send command: {}", e);
+                    break;
+                }
+
+                if let Command::Send(chat_message) = &command {
+                    println!("Message from you: {}", chat_message.message);
                 }
             }
         })
@@ -102,29 +134,30 @@ fn main() -> io::Result<()> {
             let mut temp_buffer = [0; 1024];
 
             loop {
-                let mut stream = match receiver_stream.lock() {
-                    Ok(stream) => stream,
+                // Only hold the lock for the read itself; with TLS the sender needs the same stream
+                let read = match receiver_stream.lock() {
+                    Ok(mut stream) => stream.read(&mut temp_buffer),
                     Err(e) => {
-                        eprintln!("Failed to lock stream: {}", e);
+                        error!("Failed to lock stream: {}", e);
                         break;
                     }
                 };
 
-                match stream.read(&mut temp_buffer) {
+                match read {
                     Ok(0) => {
-                        println!("[CLIENT] Connection closed by server.");
+                        println!("Connection closed by server");
                         break;
                     }
                     Ok(size) => {
                         let raw_message = String::from_utf8_lossy(&temp_buffer[..size]);
                         buffer.push_str(&raw_message);
                     }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
                         thread::sleep(Duration::from_millis(100));
                         continue;
                     }
                     Err(e) => {
-                        eprintln!("[CLIENT ERROR] Failed to read from server: {}", e);
+                        error!("Failed to read from server: {}", e);
                         break;
                     }
                 }
@@ -136,112 +169,336 @@ fn main() -> io::Result<()> {
 
                 // Process all messages in the queue
                 while let Some(message) = queue.pop_front() {
-                    println!("[CLIENT] Received message: {}", message);
-                    process_server_response(&message);
+                    debug!(line = %message, "Received");
+                    // Answer heartbeats straight away so the server does not think we are gone
+                    if let Some(reply) = process_server_response(&message) {
+                        let written = match receiver_stream.lock() {
+                            Ok(mut stream) => stream.write_all(reply.encode().as_bytes()),
+                            Err(e) => {
+                                error!("Failed to lock stream: {}", e);
+                                break;
+                            }
+                        };
+                        if let Err(e) = written {
+                            error!("Failed to reply to server: {}", e);
+                        }
+                    }
                 }
             }
         })
     };
 
     if let Err(err) = sender_thread.join() {
-        eprintln!("Error in sender thread: {:?}", err);
+        error!("Sender thread panicked: {:?}", err);
     }
 
     if let Err(err) = receiver_thread.join() {
-        eprintln!("Error in receiver thread: {:?}", err);
+        error!("Receiver thread panicked: {:?}", err);
     }
 
-    println!("[CLIENT] Threads successfully shut down.");
+    debug!("Threads shut down");
     Ok(())
 }
 
-fn is_valid_json(response: &str) -> bool {
-    serde_json::from_str::<Value>(response).is_ok()
+// CHAT_TLS_CA trusts the certificates in a PEM file, CHAT_TLS_PIN accepts exactly one server certificate;
+// with neither set the connection is plain TCP
+fn tls_config() -> io::Result<Option<ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    if let Some(path) = std::env::var_os("CHAT_TLS_PIN") {
+        let certificate = CertificateDer::from_pem_file(&path).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("could not read {}: {}", path.to_string_lossy(), e))
+        })?;
+        let verifier = PinnedCertificate { certificate, provider };
+        return Ok(Some(
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth(),
+        ));
+    }
+
+    if let Some(path) = std::env::var_os("CHAT_TLS_CA") {
+        let mut roots = RootCertStore::empty();
+        let certificates = CertificateDer::pem_file_iter(&path)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("could not read {}: {}", path.to_string_lossy(), e))
+            })?;
+        let (added, _) = roots.add_parsable_certificates(certificates);
+        if added == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no usable certificates in {}", path.to_string_lossy()),
+            ));
+        }
+        return Ok(Some(builder.with_root_certificates(roots).with_no_client_auth()));
+    }
+
+    Ok(None)
 }
 
-fn process_server_response(response_trimmed: &str) {
-    if response_trimmed.starts_with("200 BOARD") {
-        let json_data = response_trimmed.strip_prefix("200 BOARD").unwrap_or("").trim();
-        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(json_data) {
-            println!("Current userboard:");
-            if let Some(user_map) = parsed.as_object() {
-                for (user, status) in user_map {
-                    println!("{}: {}", user, status);
-                }
-            }
+// Trusts one exact certificate instead of a CA, for self-signed servers; the handshake signatures are still checked
+#[derive(Debug)]
+struct PinnedCertificate {
+    certificate: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.certificate.as_ref() {
+            Ok(ServerCertVerified::assertion())
         } else {
-            println!("Unexpected format for userboard response.");
-        }
-    } else if is_valid_json(&response_trimmed) {
-        match serde_json::from_str::<serde_json::Value>(&response_trimmed) {
-            Ok(json_response) => {
-                if let Some(sender) = json_response.get("sender").and_then(|s| s.as_str()) {
-                    if let Some(message) = json_response.get("message").and_then(|m| m.as_str()) {
-                        println!("Message from {}: {}", sender, message);
-                    } else {
-                        println!("Received a message without 'message' field: {}", json_response);
-                    }
-                } else {
-                    println!("Received a JSON object without 'sender': {}", json_response);
-                }
-            }
-            Err(e) => {
-                println!("Failed to parse JSON response: {}", response_trimmed);
-                println!("Error: {}", e);
-            }
+            Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure))
         }
-    } else {
-        match response_trimmed {
-            "200 OK" => {
-                println!("Username was accepted");
-            }
-            "400 INVALID USERNAME" => {
-                println!("Invalid username. Please try again.");
-            }
-            "200 BYE" => {
-                println!("Leaving the chatroom");
-                std::process::exit(0);
-            }
-            "200 SENT" => {
-                println!("Message in queue to be sent");
-            }
-            "400 MESSAGE FAILED" => {
-                println!("Could not send message");
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Prints a server line; returns a command to send back if the line needs an automatic reply
+fn process_server_response(response_trimmed: &str) -> Option<Command> {
+    let response = match Response::parse(response_trimmed) {
+        Ok(response) => response,
+        Err(e) => {
+            println!("Unexpected response from server: {} ({})", response_trimmed, e);
+            return None;
+        }
+    };
+
+    match response {
+        Response::Board(board) => {
+            println!("Current userboard:");
+            for (user, status) in board {
+                println!("{}: {}", user, status);
             }
-            "200 USERSTATUS UPDATED" => {
-                println!("Accepted user status change command");
+        }
+        Response::Message(message) => {
+            match message.id {
+                Some(id) => println!("Message {} from {}: {}", id, message.sender, message.message),
+                None => println!("Message from {}: {}", message.sender, message.message),
             }
-            "400 INVALID REQUEST" => {
-                println!("Could not update user status");
+            // It has just been shown, so tell the sender it was read
+            if let Some(id) = message.id {
+                return Some(Command::Ack { id, state: ReceiptState::Read });
             }
-            "500 SERVER ERROR" => {
-                println!("Server error");
+        }
+        Response::Presence(event) => match event.event {
+            PresenceKind::Join => println!("{} joined the chatroom", event.username),
+            PresenceKind::Leave => println!("{} left the chatroom", event.username),
+            PresenceKind::Status => println!("{} is now {}", event.username, event.status),
+        },
+        Response::Ok => {
+            println!("Username was accepted");
+        }
+        Response::InvalidUsername => {
+            println!("Invalid username. Please try again.");
+        }
+        Response::Registered => {
+            println!("Account created, you can now LOGIN");
+        }
+        Response::InvalidPassword => {
+            println!("Passwords must be at least 8 characters with no spaces");
+        }
+        Response::AccountExists => {
+            println!("That username is already registered");
+        }
+        Response::InvalidCredentials => {
+            println!("Wrong username or password");
+        }
+        Response::LoginRequired => {
+            println!("This server does not allow guests, use REGISTER and LOGIN");
+        }
+        Response::AlreadyLoggedIn => {
+            println!("That account is already logged in somewhere else");
+        }
+        Response::Shutdown(reason) => match reason {
+            Some(reason) => println!("The server is shutting down: {}", reason),
+            None => println!("The server is shutting down"),
+        },
+        Response::AlreadyJoined => {
+            println!("You have already joined the chatroom");
+        }
+        Response::NotJoined => {
+            println!("Join the chatroom first with JOIN <username>");
+        }
+        Response::Bye => {
+            println!("Leaving the chatroom");
+            std::process::exit(0);
+        }
+        Response::Sent(id) => {
+            println!("Message {} in queue to be sent", id);
+        }
+        Response::Queued(id) => {
+            println!("Recipient is offline, message {} will be delivered when they return", id);
+        }
+        Response::Acked => {
+            // Sent automatically for every message shown, so there is nothing to tell the user
+        }
+        Response::NoSuchMessage => {
+            println!("No such message, or the server no longer has it");
+        }
+        Response::MessageEdited => {
+            println!("Message edited");
+        }
+        Response::MessageDeleted => {
+            println!("Message deleted");
+        }
+        Response::NotYourMessage => {
+            println!("You can only change your own messages");
+        }
+        Response::Edited(edit) => {
+            println!("Message {} was edited: {}", edit.id, edit.message);
+        }
+        Response::Deleted(id) => {
+            println!("Message {} was deleted", id);
+        }
+        Response::Typing(event) => {
+            println!("{} is typing...", event.username);
+        }
+        Response::Receipt(receipt) => match receipt.state {
+            ReceiptState::Delivered => println!("Message {} was delivered to {}", receipt.id, receipt.username),
+            ReceiptState::Read => println!("{} has read message {}", receipt.username, receipt.id),
+        },
+        Response::MessageFailed => {
+            println!("Could not send message");
+        }
+        Response::InvalidMessageFormat => {
+            println!("Message was not in a valid format");
+        }
+        Response::StatusUpdated => {
+            println!("Accepted user status change command");
+        }
+        Response::RoomJoined(room) => {
+            println!("Joined #{}", room);
+        }
+        Response::RoomLeft(room) => {
+            println!("Left #{}", room);
+        }
+        Response::Rooms(rooms) => {
+            println!("Current rooms:");
+            for (room, members) in rooms {
+                println!("#{}: {}", room, members.join(", "));
             }
-            "100 TESTING" => {
-                println!("Testing message received");
+        }
+        Response::History(page) => {
+            println!("History for {} ({} messages):", page.target, page.messages.len());
+            for entry in &page.messages {
+                println!(
+                    "[{}] {} {}: {}",
+                    entry.id,
+                    entry.message.timestamp.as_deref().unwrap_or("--:--"),
+                    entry.message.sender,
+                    entry.message.message
+                );
             }
-            _ => {
-                println!("Unexpected response from server: {}", response_trimmed);
+            if let Some(oldest) = page.messages.first() {
+                println!("For older messages use: HISTORY {} {} {}", page.target, page.messages.len(), oldest.id);
             }
         }
-    }
-}
-
-fn process_send_message(input: &str) -> Option<String> {
-    println!("process_send_message called");
-    match serde_json::from_str::<Value>(input) {
-        Ok(mut json_obj) => {
-            if let Some(obj) = json_obj.as_object_mut() {
-                let timestamp = Utc::now().format("%H:%M").to_string();
-                obj.insert("timestamp".to_string(), Value::String(timestamp));
-                return Some(json_obj.to_string());
-            } else {
-                println!("Invalid SEND command: JSON must be an object.");
-            }
+        Response::InvalidRoomName => {
+            println!("Room names must be 1 to 30 letters, digits, '-' or '_'");
         }
-        Err(e) => {
-            println!("Failed to parse JSON: {}", e);
+        Response::NotInRoom => {
+            println!("You are not in that room");
+        }
+        Response::NoSuchRoom => {
+            println!("That room does not exist");
+        }
+        Response::NotYourUser => {
+            println!("You can only change your own status");
+        }
+        Response::InvalidRequest => {
+            println!("The server could not carry out that request; check the command and its arguments");
+        }
+        Response::LineTooLong => {
+            println!("Command was too long for the server");
+        }
+        Response::IdleTimeout => {
+            println!("Disconnected for being idle too long");
+        }
+        Response::AdminGranted => {
+            println!("You are now an admin");
+        }
+        Response::NotAdmin => {
+            println!("Only admins can do that");
+        }
+        Response::NoSuchUser => {
+            println!("No such user, ban or mute");
+        }
+        Response::UserKicked => {
+            println!("User was kicked");
+        }
+        Response::UserBanned => {
+            println!("Ban added");
+        }
+        Response::UserUnbanned => {
+            println!("Ban lifted");
+        }
+        Response::UserMuted => {
+            println!("User was muted");
+        }
+        Response::UserUnmuted => {
+            println!("User was unmuted");
+        }
+        Response::Banned => {
+            println!("You are banned from this server");
+        }
+        Response::Muted => {
+            println!("You are muted and cannot send messages right now");
+        }
+        Response::Kicked(reason) => match reason {
+            Some(reason) => println!("You were kicked by an admin: {}", reason),
+            None => println!("You were kicked by an admin"),
+        },
+        Response::Ping(token) => {
+            return Some(Command::Pong(token));
+        }
+        Response::Pong(_) => {
+            println!("PONG");
+        }
+        Response::ServerError => {
+            println!("Server error");
+        }
+        Response::Testing => {
+            println!("Testing message received");
         }
     }
     None
-}
\ No newline at end of file
+}
+
+fn process_send_message(mut message: ChatMessage) -> ChatMessage {
+    message.timestamp = Some(Utc::now().format("%H:%M").to_string());
+    message
+}
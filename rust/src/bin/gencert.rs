@@ -0,0 +1,37 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+// Generates a self-signed certificate and key for trying TLS locally.
+// Usage: gencert [name ...]
+// The names default to localhost, 127.0.0.1 and this machine's LAN address.
+fn main() -> io::Result<()> {
+    let mut names: Vec<String> = std::env::args().skip(1).collect();
+    if names.is_empty() {
+        names.push("localhost".to_string());
+        names.push("127.0.0.1".to_string());
+        if let Ok(local_ip) = local_ip_address::local_ip() {
+            names.push(local_ip.to_string());
+        }
+    }
+
+    let certified = rcgen::generate_simple_self_signed(names.clone())
+        .map_err(|e| io::Error::other(format!("could not generate certificate: {}", e)))?;
+
+    write_new("cert.pem", &certified.cert.pem())?;
+    write_new("key.pem", &certified.signing_key.serialize_pem())?;
+
+    println!("Wrote cert.pem and key.pem for {}", names.join(", "));
+    println!("Server: CHAT_TLS_CERT=cert.pem CHAT_TLS_KEY=key.pem cargo run --bin server");
+    println!("Client: CHAT_TLS_CA=cert.pem cargo run --bin client");
+    Ok(())
+}
+
+// Refuses to overwrite, so an existing key is never lost by accident
+fn write_new(path: &str, contents: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    file.write_all(contents.as_bytes())
+}
@@ -0,0 +1,150 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tracing::error;
+
+#[derive(Debug)]
+pub enum AccountError {
+    Exists,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for AccountError {
+    fn from(e: std::io::Error) -> Self {
+        AccountError::Io(e)
+    }
+}
+
+// Registered usernames and their argon2 password hashes, saved as a JSON object
+pub struct Accounts {
+    path: PathBuf,
+    hashes: RwLock<BTreeMap<String, String>>,
+}
+
+impl Accounts {
+    pub fn open(path: PathBuf) -> std::io::Result<Self> {
+        let hashes = if path.exists() {
+            let contents = std::fs::read_to_string(&path)?;
+            serde_json::from_str(&contents)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Accounts {
+            path,
+            hashes: RwLock::new(hashes),
+        })
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn exists(&self, username: &str) -> bool {
+        self.hashes.read().unwrap().contains_key(username)
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.hashes.read().unwrap().keys().cloned().collect()
+    }
+
+    // Hashes the password with a fresh salt and saves the new account to disk
+    pub fn register(&self, username: &str, password: &str) -> Result<(), AccountError> {
+        if self.exists(username) {
+            return Err(AccountError::Exists);
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| std::io::Error::other(e.to_string()))?
+            .to_string();
+
+        let mut hashes = self.hashes.write().unwrap();
+        // Someone else may have registered the name while we were hashing
+        if hashes.contains_key(username) {
+            return Err(AccountError::Exists);
+        }
+        hashes.insert(username.to_string(), hash);
+
+        if let Err(e) = self.save(&hashes) {
+            hashes.remove(username);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let Some(hash) = self.hashes.read().unwrap().get(username).cloned() else {
+            return false;
+        };
+        match PasswordHash::new(&hash) {
+            Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+            Err(e) => {
+                error!(%username, error = %e, "Stored password hash is unreadable");
+                false
+            }
+        }
+    }
+
+    // Writes to a temporary file first so a crash never leaves a half-written accounts file
+    fn save(&self, hashes: &BTreeMap<String, String>) -> std::io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, serde_json::to_string_pretty(hashes)?)?;
+        std::fs::rename(&temporary, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    fn accounts(name: &str) -> (Accounts, PathBuf) {
+        let path = std::env::temp_dir().join(format!("accounts-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (Accounts::open(path.clone()).unwrap(), path)
+    }
+
+    #[test]
+    fn registered_passwords_verify_and_survive_a_reopen() {
+        let (accounts, path) = accounts("verify");
+        accounts.register("alice", "correct horse").unwrap();
+        assert!(matches!(accounts.register("alice", "another one"), Err(AccountError::Exists)));
+
+        let reopened = Accounts::open(path.clone()).unwrap();
+        assert!(reopened.verify("alice", "correct horse"));
+        assert!(!reopened.verify("alice", "another one"));
+        assert!(!reopened.verify("bob", "correct horse"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn only_one_of_two_racing_registrations_wins() {
+        let (accounts, path) = accounts("race");
+        let start = Barrier::new(2);
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let racers: Vec<_> = ["first password", "second password"]
+                .into_iter()
+                .map(|password| {
+                    let (accounts, start) = (&accounts, &start);
+                    scope.spawn(move || {
+                        start.wait();
+                        accounts.register("alice", password)
+                    })
+                })
+                .collect();
+            racers.into_iter().map(|racer| racer.join().unwrap()).collect()
+        });
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results.iter().any(|result| matches!(result, Err(AccountError::Exists))));
+        // Whichever won, its password is the one that was saved
+        let winner = if results[0].is_ok() { "first password" } else { "second password" };
+        assert!(Accounts::open(path.clone()).unwrap().verify("alice", winner));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{route_message, Server};
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response as HttpResponse};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Utc;
+use homework4::protocol::{ChatMessage, Response};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+// Answers the REST API until the task is aborted at shutdown
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    let app = Router::new()
+        .route("/users", get(users))
+        .route("/messages", post(post_message))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&server), require_token))
+        .with_state(server);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!(error = %e, "API listener failed");
+    }
+}
+
+// Every route needs `Authorization: Bearer <CHAT_API_TOKEN>`
+async fn require_token(State(server): State<Arc<Server>>, request: Request, next: Next) -> HttpResponse {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if given.is_some() && given == server.api_token.as_deref() {
+        return next.run(request).await;
+    }
+    warn!(path = %request.uri().path(), "API request with a missing or wrong token");
+    let body = Json(json!({ "response": "INVALID TOKEN" }));
+    (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response()
+}
+
+// The same username to status map as USERBOARD
+async fn users(State(server): State<Arc<Server>>) -> HttpResponse {
+    Json(server.registry.board()).into_response()
+}
+
+// Delivered like SEND, always from the configured API sender name
+async fn post_message(State(server): State<Arc<Server>>, Json(mut message): Json<ChatMessage>) -> HttpResponse {
+    message.sender = server.config.api_sender.clone();
+    // Clients stamp their own messages and skip ones without a time, so stamp API posts the same way
+    if message.timestamp.is_none() {
+        message.timestamp = Some(Utc::now().format("%H:%M").to_string());
+    }
+    info!(header = %message.header, "Message posted over the API");
+    protocol_reply(&route_message(message, None, &server))
+}
+
+// A protocol reply such as `403 NOT IN ROOM` becomes that HTTP status with {"response": "NOT IN ROOM"}
+fn protocol_reply(response: &Response) -> HttpResponse {
+    // Accepted messages also report the ID recipients will see
+    match response {
+        Response::Sent(id) => return Json(json!({ "response": "SENT", "id": id })).into_response(),
+        Response::Queued(id) => return Json(json!({ "response": "QUEUED", "id": id })).into_response(),
+        _ => {}
+    }
+    let line = response.encode();
+    let (code, text) = line.trim_end().split_once(' ').unwrap_or(("500", "SERVER ERROR"));
+    let status = code
+        .parse()
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, Json(json!({ "response": text }))).into_response()
+}
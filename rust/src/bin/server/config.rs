@@ -0,0 +1,382 @@
+use crate::logging::{LogFormat, LogLevel};
+use clap::Parser;
+use homework4::protocol::MAX_MESSAGE_LENGTH;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DEFAULT_PORT: u16 = 8000;
+
+// Longest command line accepted from a client, not counting the trailing newline
+const DEFAULT_MAX_LINE_LENGTH: usize = 4096;
+
+// Longest allowed timeout, a week; far beyond any sensible setting, and it keeps deadlines well inside the clock's range
+const MAX_TIMEOUT_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Chat room server. Settings come from the defaults, then the config file, then these flags.
+#[derive(Parser, Debug)]
+#[command(name = "server")]
+struct Cli {
+    /// TOML file to read settings from
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Address to listen on; repeat to listen on several
+    #[arg(short, long)]
+    bind: Vec<IpAddr>,
+    /// Port to listen on [default: 8000]
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Most detailed log level to print [default: info]
+    #[arg(long)]
+    log_level: Option<LogLevel>,
+    /// Write logs as text or as JSON lines [default: text]
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+    /// Also accept WebSocket connections on this port, one protocol line per text frame [default: off]
+    #[arg(long)]
+    websocket_port: Option<u16>,
+    /// Serve Prometheus metrics at http://127.0.0.1:<PORT>/metrics [default: off]
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Serve the REST API at http://127.0.0.1:<PORT>; needs CHAT_API_TOKEN [default: off]
+    #[arg(long)]
+    api_port: Option<u16>,
+    /// Fewest characters in a SEND message [default: 1]
+    #[arg(long)]
+    min_message_length: Option<usize>,
+    /// Most characters in a SEND message [default: 500]
+    #[arg(long)]
+    max_message_length: Option<usize>,
+    /// Longest command line in bytes [default: 4096]
+    #[arg(long)]
+    max_line_length: Option<usize>,
+    /// Reason sent to connected users when the server shuts down
+    #[arg(long)]
+    shutdown_reason: Option<String>,
+    /// Seconds of silence before the server sends a PING [default: 30]
+    #[arg(long)]
+    heartbeat_interval: Option<u64>,
+    /// Seconds to wait for any reply to a PING before disconnecting [default: 30]
+    #[arg(long)]
+    heartbeat_timeout: Option<u64>,
+    /// Seconds without a command, not counting PING and PONG, before disconnecting; 0 turns it off [default: 0]
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// Seconds a typing indicator lasts unless it is repeated [default: 5]
+    #[arg(long)]
+    typing_timeout: Option<u64>,
+    /// Shortest allowed username [default: 3]
+    #[arg(long)]
+    min_username_length: Option<usize>,
+    /// Longest allowed username [default: 30]
+    #[arg(long)]
+    max_username_length: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Empty means this machine's LAN address, or 127.0.0.1 if it has none
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    // Second port for browser clients, on the same addresses; unset means no WebSocket listener
+    pub websocket_port: Option<u16>,
+    // Local-only port for the /metrics endpoint; unset means no metrics listener
+    pub metrics_port: Option<u16>,
+    // Local-only port for the REST API; unset means no API listener
+    pub api_port: Option<u16>,
+    // Sender name on messages posted through the API; reserved so no user can take it
+    pub api_sender: String,
+    // Included in the SHUTDOWN notice, e.g. "restarting for an upgrade"
+    pub shutdown_reason: Option<String>,
+    pub messages: MessageLimits,
+    pub usernames: UsernameRules,
+    pub timeouts: Timeouts,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageLimits {
+    // Characters in a SEND message body, after trimming
+    pub min_length: usize,
+    pub max_length: usize,
+    pub max_line_length: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameRules {
+    pub min_length: usize,
+    pub max_length: usize,
+    // Names nobody may take, compared case-insensitively
+    pub reserved: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    // All in seconds
+    pub heartbeat_interval: u64,
+    pub heartbeat_timeout: u64,
+    // 0 means idle connections are never dropped
+    pub idle: u64,
+    // How long recipients show a TYPING indicator
+    pub typing: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: Vec::new(),
+            port: DEFAULT_PORT,
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            websocket_port: None,
+            metrics_port: None,
+            api_port: None,
+            api_sender: "api".to_string(),
+            shutdown_reason: None,
+            messages: MessageLimits::default(),
+            usernames: UsernameRules::default(),
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            heartbeat_interval: 30,
+            heartbeat_timeout: 30,
+            idle: 0,
+            typing: 5,
+        }
+    }
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            min_length: 1,
+            max_length: MAX_MESSAGE_LENGTH,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+}
+
+impl Default for UsernameRules {
+    fn default() -> Self {
+        UsernameRules {
+            min_length: 3,
+            max_length: 30,
+            reserved: vec!["all".to_string()],
+        }
+    }
+}
+
+impl MessageLimits {
+    pub fn allows(&self, length: usize) -> bool {
+        (self.min_length..=self.max_length).contains(&length)
+    }
+}
+
+impl UsernameRules {
+    pub fn allows(&self, username: &str) -> bool {
+        (self.min_length..=self.max_length).contains(&username.chars().count())
+            && username.chars().all(|c| c.is_alphanumeric())
+            && !self.reserved.iter().any(|reserved| reserved.eq_ignore_ascii_case(username))
+    }
+}
+
+impl Timeouts {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_timeout)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        (self.idle > 0).then(|| Duration::from_secs(self.idle))
+    }
+
+    // A user's TYPING is relayed at most twice per timeout, often enough to keep the indicator showing
+    pub fn typing_interval(&self) -> Duration {
+        Duration::from_secs(self.typing) / 2
+    }
+
+    // When a connection should next be sent a PING, or dropped if one is already unanswered, and when it should be
+    // dropped for sending no commands
+    pub fn deadlines(&self, last_received: Instant, last_command: Instant, ping_sent: Option<Instant>) -> (Instant, Option<Instant>) {
+        let heartbeat = match ping_sent {
+            Some(sent) => sent + self.heartbeat_timeout(),
+            None => last_received + self.heartbeat_interval(),
+        };
+        (heartbeat, self.idle().map(|idle| last_command + idle))
+    }
+}
+
+impl ServerConfig {
+    // Parses the command line, reads the config file it names, and applies the flags on top
+    pub fn load() -> Result<Self, String> {
+        let cli = Cli::parse();
+
+        let mut config = match &cli.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+                toml::from_str(&contents).map_err(|e| format!("invalid config file {}: {}", path.display(), e))?
+            }
+            None => ServerConfig::default(),
+        };
+
+        if !cli.bind.is_empty() {
+            config.bind = cli.bind;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if let Some(level) = cli.log_level {
+            config.log_level = level;
+        }
+        if let Some(format) = cli.log_format {
+            config.log_format = format;
+        }
+        if let Some(port) = cli.websocket_port {
+            config.websocket_port = Some(port);
+        }
+        if let Some(port) = cli.metrics_port {
+            config.metrics_port = Some(port);
+        }
+        if let Some(port) = cli.api_port {
+            config.api_port = Some(port);
+        }
+        if let Some(reason) = cli.shutdown_reason {
+            config.shutdown_reason = Some(reason);
+        }
+        if let Some(length) = cli.min_message_length {
+            config.messages.min_length = length;
+        }
+        if let Some(length) = cli.max_message_length {
+            config.messages.max_length = length;
+        }
+        if let Some(length) = cli.max_line_length {
+            config.messages.max_line_length = length;
+        }
+        if let Some(seconds) = cli.heartbeat_interval {
+            config.timeouts.heartbeat_interval = seconds;
+        }
+        if let Some(seconds) = cli.heartbeat_timeout {
+            config.timeouts.heartbeat_timeout = seconds;
+        }
+        if let Some(seconds) = cli.idle_timeout {
+            config.timeouts.idle = seconds;
+        }
+        if let Some(seconds) = cli.typing_timeout {
+            config.timeouts.typing = seconds;
+        }
+        if let Some(length) = cli.min_username_length {
+            config.usernames.min_length = length;
+        }
+        if let Some(length) = cli.max_username_length {
+            config.usernames.max_length = length;
+        }
+
+        if config.bind.is_empty() {
+            config.bind.push(local_ip_address::local_ip().unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        }
+
+        config.validate()?;
+        let api_sender = &config.api_sender;
+        if config.api_port.is_some() && !config.usernames.reserved.iter().any(|name| name.eq_ignore_ascii_case(api_sender)) {
+            config.usernames.reserved.push(api_sender.clone());
+        }
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.websocket_port == Some(self.port) {
+            return Err("websocket_port must differ from port".to_string());
+        }
+        if self.api_sender.is_empty() || !self.api_sender.chars().all(char::is_alphanumeric) {
+            return Err("api_sender must be made of letters and digits".to_string());
+        }
+        if let Some(reason) = &self.shutdown_reason {
+            if reason.chars().any(char::is_control) {
+                return Err("shutdown_reason must be a single line".to_string());
+            }
+        }
+        let messages = &self.messages;
+        if messages.min_length == 0 || messages.min_length > messages.max_length {
+            return Err("messages.min_length must be at least 1 and no more than messages.max_length".to_string());
+        }
+        if messages.max_line_length < messages.max_length {
+            return Err("messages.max_line_length must be at least messages.max_length".to_string());
+        }
+        let usernames = &self.usernames;
+        if usernames.min_length == 0 || usernames.min_length > usernames.max_length {
+            return Err("usernames.min_length must be at least 1 and no more than usernames.max_length".to_string());
+        }
+        if self.timeouts.heartbeat_interval == 0 || self.timeouts.heartbeat_timeout == 0 {
+            return Err("timeouts.heartbeat_interval and timeouts.heartbeat_timeout must be at least 1 second".to_string());
+        }
+        if self.timeouts.typing == 0 {
+            return Err("timeouts.typing must be at least 1 second".to_string());
+        }
+        let timeouts = &self.timeouts;
+        if [timeouts.heartbeat_interval, timeouts.heartbeat_timeout, timeouts.idle, timeouts.typing]
+            .iter()
+            .any(|&secs| secs > MAX_TIMEOUT_SECS)
+        {
+            return Err(format!("timeouts must be at most {} seconds (a week)", MAX_TIMEOUT_SECS));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_past_a_week_are_refused() {
+        let mut config = ServerConfig::default();
+        assert!(config.validate().is_ok());
+        config.timeouts.heartbeat_interval = u64::MAX;
+        assert!(config.validate().is_err());
+
+        config.timeouts.heartbeat_interval = MAX_TIMEOUT_SECS;
+        config.timeouts.idle = MAX_TIMEOUT_SECS + 1;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn deadlines_follow_the_last_traffic_and_any_ping() {
+        let timeouts = Timeouts {
+            heartbeat_interval: 30,
+            heartbeat_timeout: 10,
+            idle: 0,
+            typing: 5,
+        };
+        let received = Instant::now();
+        let command = received - Duration::from_secs(100);
+
+        let (heartbeat, idle) = timeouts.deadlines(received, command, None);
+        assert_eq!(heartbeat, received + Duration::from_secs(30));
+        assert_eq!(idle, None);
+
+        // Once a PING is out, only its reply timeout matters
+        let pinged = received + Duration::from_secs(30);
+        let (heartbeat, _) = timeouts.deadlines(received, command, Some(pinged));
+        assert_eq!(heartbeat, pinged + Duration::from_secs(10));
+
+        let timeouts = Timeouts { idle: 600, ..timeouts };
+        let (_, idle) = timeouts.deadlines(received, command, None);
+        assert_eq!(idle, Some(command + Duration::from_secs(600)));
+    }
+}
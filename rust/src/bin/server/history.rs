@@ -0,0 +1,635 @@
+use crate::session::{Identity, SessionId};
+use homework4::protocol::{ChatMessage, HistoryEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+const DEFAULT_LIMIT: usize = 200;
+
+// Starts the key of an unlisted conversation; room names cannot contain it
+const UNLISTED_PREFIX: char = '~';
+
+#[derive(Debug, Clone)]
+pub struct HistoryConfig {
+    // Messages kept per conversation
+    pub limit: usize,
+    // Append-only JSON lines file; history is memory-only when unset
+    pub path: Option<PathBuf>,
+}
+
+impl HistoryConfig {
+    // Reads CHAT_HISTORY_LIMIT and CHAT_HISTORY_FILE
+    pub fn from_env() -> Self {
+        let limit = std::env::var("CHAT_HISTORY_LIMIT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&limit| limit > 0)
+            .unwrap_or(DEFAULT_LIMIT);
+        let path = std::env::var_os("CHAT_HISTORY_FILE").map(PathBuf::from);
+
+        HistoryConfig { limit, path }
+    }
+}
+
+// A stream of messages that can be paged through with HISTORY
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    All,
+    Room(String),
+    // Private messages between two users, stored in sorted order so either side finds it
+    Direct(String, String),
+    // Private messages with a guest, kept with that guest's session only so they can still be edited.
+    // HISTORY cannot page them, they are not saved to the history file, and they go when the session ends.
+    Unlisted(SessionId),
+}
+
+impl Conversation {
+    pub fn direct(a: &str, b: &str) -> Self {
+        if a <= b {
+            Conversation::Direct(a.to_string(), b.to_string())
+        } else {
+            Conversation::Direct(b.to_string(), a.to_string())
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            Conversation::All => "@all".to_string(),
+            Conversation::Room(room) => format!("#{}", room),
+            Conversation::Direct(a, b) => format!("@{}@{}", a, b),
+            Conversation::Unlisted(session) => format!("{}{}", UNLISTED_PREFIX, session),
+        }
+    }
+}
+
+fn is_unlisted(key: &str) -> bool {
+    key.starts_with(UNLISTED_PREFIX)
+}
+
+// Who a message came from and went to when it was sent, for deciding who may change it and who hears about it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Audience {
+    // None for messages from outside any session, such as the REST API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<Identity>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<Identity>,
+}
+
+impl Audience {
+    fn is_empty(&self) -> bool {
+        self.sender.is_none() && self.recipients.is_empty()
+    }
+
+    // Guest sessions from an earlier run are gone, and their IDs will be handed out again
+    fn without_guests(mut self) -> Self {
+        self.sender = self.sender.filter(|sender| matches!(sender, Identity::Account(_)));
+        self.recipients.retain(|recipient| matches!(recipient, Identity::Account(_)));
+        self
+    }
+}
+
+// One line of the history file; a later line with the same ID replaces the message, or removes it if deleted
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    conversations: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+    #[serde(default, skip_serializing_if = "Audience::is_empty")]
+    audience: Audience,
+    #[serde(flatten)]
+    entry: HistoryEntry,
+}
+
+// A message's audience, kept while any conversation still holds the message
+struct Held {
+    conversations: usize,
+    audience: Audience,
+}
+
+struct HistoryState {
+    next_id: u64,
+    conversations: HashMap<String, VecDeque<HistoryEntry>>,
+    held: HashMap<u64, Held>,
+}
+
+pub struct History {
+    limit: usize,
+    state: Mutex<HistoryState>,
+    persist: Mutex<Option<mpsc::UnboundedSender<String>>>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl History {
+    // Loads any existing history file, compacts it to what is kept in memory, and starts appending to it
+    pub fn open(config: &HistoryConfig) -> std::io::Result<Self> {
+        let mut history = History {
+            limit: config.limit,
+            state: Mutex::new(HistoryState {
+                next_id: 1,
+                conversations: HashMap::new(),
+                held: HashMap::new(),
+            }),
+            persist: Mutex::new(None),
+            writer: Mutex::new(None),
+        };
+
+        let Some(path) = &config.path else {
+            return Ok(history);
+        };
+
+        if path.exists() {
+            let contents = std::fs::read_to_string(path)?;
+            let mut retained = BTreeMap::new();
+            // Kept through compaction when it is the newest line, so a deleted ID is never handed out again
+            let mut newest_deletion: Option<StoredEntry> = None;
+            {
+                let mut state = history.state.lock().unwrap();
+                for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+                    match serde_json::from_str::<StoredEntry>(line) {
+                        Ok(stored) => {
+                            let id = stored.entry.id;
+                            state.next_id = state.next_id.max(id + 1);
+                            if stored.deleted {
+                                replace(&mut state, &stored.conversations, id, None);
+                                retained.remove(&id);
+                                if newest_deletion.as_ref().is_none_or(|newest| newest.entry.id < id) {
+                                    newest_deletion = Some(stored);
+                                }
+                            } else {
+                                if retained.contains_key(&id) {
+                                    replace(&mut state, &stored.conversations, id, Some(&stored.entry));
+                                } else {
+                                    hold(&mut state, id, stored.audience.clone().without_guests());
+                                    for key in &stored.conversations {
+                                        history.push(&mut state, key, stored.entry.clone());
+                                    }
+                                }
+                                retained.insert(id, stored);
+                            }
+                        }
+                        Err(e) => error!(error = %e, "Skipping bad history line"),
+                    }
+                }
+
+                // Drop anything that has aged out of every conversation
+                let kept: HashSet<u64> = state
+                    .conversations
+                    .values()
+                    .flat_map(|entries| entries.iter().map(|entry| entry.id))
+                    .collect();
+                retained.retain(|id, _| kept.contains(id));
+            }
+            let high_water = newest_deletion
+                .filter(|deletion| retained.last_key_value().is_none_or(|(&id, _)| id < deletion.entry.id));
+
+            let compacted = path.with_extension("compact");
+            let mut file = std::fs::File::create(&compacted)?;
+            for stored in retained.values().chain(&high_water) {
+                writeln!(file, "{}", serde_json::to_string(stored)?)?;
+            }
+            file.sync_all()?;
+            std::fs::rename(&compacted, path)?;
+            info!(count = retained.len(), path = %path.display(), "Loaded history");
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        history.writer = Mutex::new(Some(tokio::spawn(append_lines(path.clone(), receiver))));
+        history.persist = Mutex::new(Some(sender));
+
+        Ok(history)
+    }
+
+    // Hands out the next message ID; IDs are taken before delivery so recipients see them too
+    pub fn next_id(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        state.next_id - 1
+    }
+
+    // Stores a relayed message, under the ID it was given, in each conversation it belongs to
+    pub fn record(&self, id: u64, conversations: &[Conversation], message: &ChatMessage, audience: Audience) {
+        let mut state = self.state.lock().unwrap();
+        // The entry carries the ID, so the flattened message must not repeat it
+        let entry = HistoryEntry {
+            id,
+            message: ChatMessage {
+                id: None,
+                ..message.clone()
+            },
+        };
+
+        let keys: Vec<String> = conversations.iter().map(Conversation::key).collect();
+        hold(&mut state, id, audience.clone());
+        for key in &keys {
+            self.push(&mut state, key, entry.clone());
+        }
+
+        if keys.iter().all(|key| is_unlisted(key)) {
+            // Only the ID of an unlisted message is saved, as a deletion, so a restart does not hand it out again
+            self.persist(StoredEntry {
+                conversations: Vec::new(),
+                deleted: true,
+                audience: Audience::default(),
+                entry: HistoryEntry {
+                    id,
+                    message: ChatMessage::default(),
+                },
+            });
+            return;
+        }
+        self.persist(StoredEntry {
+            conversations: keys,
+            deleted: false,
+            audience,
+            entry,
+        });
+    }
+
+    // A message still held in any conversation and who it was between, for checking who may change it
+    pub fn find(&self, id: u64) -> Option<(ChatMessage, Audience)> {
+        let state = self.state.lock().unwrap();
+        let (_, entry) = locate(&state, id)?;
+        let audience = state.held.get(&id).map(|held| held.audience.clone()).unwrap_or_default();
+        Some((entry.message, audience))
+    }
+
+    // Replaces a message's text everywhere it is stored; returns false if it is no longer held
+    pub fn edit(&self, id: u64, text: &str, edited_at: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some((keys, mut entry)) = locate(&state, id) else {
+            return false;
+        };
+        entry.message.message = text.to_string();
+        entry.message.edited_at = Some(edited_at.to_string());
+        replace(&mut state, &keys, id, Some(&entry));
+
+        // Compaction keeps only the last line for each ID, so it has to repeat the audience
+        let audience = state.held.get(&id).map(|held| held.audience.clone()).unwrap_or_default();
+        self.persist(StoredEntry {
+            conversations: keys,
+            deleted: false,
+            audience,
+            entry,
+        });
+        true
+    }
+
+    // Removes a message from every conversation; returns false if it is no longer held
+    pub fn delete(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some((keys, mut entry)) = locate(&state, id) else {
+            return false;
+        };
+        replace(&mut state, &keys, id, None);
+        // Only the ID matters to a deletion line, so the deleted text is not written out again
+        entry.message.message.clear();
+
+        self.persist(StoredEntry {
+            conversations: keys,
+            deleted: true,
+            audience: Audience::default(),
+            entry,
+        });
+        true
+    }
+
+    // Drops a guest session's unlisted messages once it has gone, since nobody can reach them any more
+    pub fn forget_unlisted(&self, session: SessionId) {
+        let mut state = self.state.lock().unwrap();
+        let Some(entries) = state.conversations.remove(&Conversation::Unlisted(session).key()) else {
+            return;
+        };
+        for entry in entries {
+            release(&mut state, entry.id);
+        }
+    }
+
+    // Up to `count` messages older than `before`, oldest first
+    pub fn page(&self, conversation: &Conversation, count: usize, before: Option<u64>) -> Vec<HistoryEntry> {
+        let state = self.state.lock().unwrap();
+        let Some(entries) = state.conversations.get(&conversation.key()) else {
+            return Vec::new();
+        };
+
+        let mut page: Vec<HistoryEntry> = entries
+            .iter()
+            .rev()
+            .filter(|entry| before.is_none_or(|before| entry.id < before))
+            .take(count)
+            .cloned()
+            .collect();
+        page.reverse();
+        page
+    }
+
+    // Stops persisting and waits for everything already recorded to reach the file
+    pub async fn flush(&self) {
+        self.persist.lock().unwrap().take();
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            let _ = writer.await;
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // Unlisted conversations are left out, and so is a line about nothing but them; a bare ID line has none to begin with
+    fn persist(&self, mut stored: StoredEntry) {
+        let bare = stored.conversations.is_empty();
+        stored.conversations.retain(|key| !is_unlisted(key));
+        if !bare && stored.conversations.is_empty() {
+            return;
+        }
+        if let Some(persist) = &*self.persist.lock().unwrap() {
+            match serde_json::to_string(&stored) {
+                Ok(line) => {
+                    let _ = persist.send(line);
+                }
+                Err(e) => error!(id = stored.entry.id, error = %e, "Failed to encode history entry"),
+            }
+        }
+    }
+
+    // IDs are handed out before delivery, so concurrent SENDs can arrive here out of order; paging needs them sorted
+    fn push(&self, state: &mut HistoryState, key: &str, entry: HistoryEntry) {
+        if let Some(held) = state.held.get_mut(&entry.id) {
+            held.conversations += 1;
+        }
+        let entries = state.conversations.entry(key.to_string()).or_default();
+        let position = entries.partition_point(|stored| stored.id < entry.id);
+        entries.insert(position, entry);
+        let mut dropped = Vec::new();
+        while entries.len() > self.limit {
+            dropped.extend(entries.pop_front().map(|entry| entry.id));
+        }
+        for id in dropped {
+            release(state, id);
+        }
+    }
+}
+
+// The conversations holding a message, and the message itself
+fn locate(state: &HistoryState, id: u64) -> Option<(Vec<String>, HistoryEntry)> {
+    let mut found = None;
+    let mut keys = Vec::new();
+    for (key, entries) in &state.conversations {
+        if let Some(entry) = entries.iter().find(|entry| entry.id == id) {
+            keys.push(key.clone());
+            found.get_or_insert_with(|| entry.clone());
+        }
+    }
+    found.map(|entry| (keys, entry))
+}
+
+// Swaps in a new version of a message in each of `keys`, or removes it when there is none
+fn replace(state: &mut HistoryState, keys: &[String], id: u64, entry: Option<&HistoryEntry>) {
+    let mut removed = 0;
+    for key in keys {
+        let Some(entries) = state.conversations.get_mut(key) else {
+            continue;
+        };
+        let Some(position) = entries.iter().position(|stored| stored.id == id) else {
+            continue;
+        };
+        match entry {
+            Some(entry) => entries[position] = entry.clone(),
+            None => {
+                entries.remove(position);
+                removed += 1;
+            }
+        }
+    }
+    for _ in 0..removed {
+        release(state, id);
+    }
+}
+
+// Starts counting the conversations that hold a message; `push` adds to the count
+fn hold(state: &mut HistoryState, id: u64, audience: Audience) {
+    state.held.insert(id, Held { conversations: 0, audience });
+}
+
+// Forgets a message's audience once no conversation holds it any more
+fn release(state: &mut HistoryState, id: u64) {
+    if let Some(held) = state.held.get_mut(&id) {
+        held.conversations = held.conversations.saturating_sub(1);
+        if held.conversations == 0 {
+            state.held.remove(&id);
+        }
+    }
+}
+
+async fn append_lines(path: PathBuf, mut receiver: mpsc::UnboundedReceiver<String>) {
+    let mut file = match tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!(path = %path.display(), error = %e, "Could not open history file");
+            return;
+        }
+    };
+
+    while let Some(line) = receiver.recv().await {
+        let result = async {
+            file.write_all(line.as_bytes()).await?;
+            file.write_all(b"\n").await?;
+            file.flush().await
+        }
+        .await;
+        if let Err(e) = result {
+            error!(path = %path.display(), error = %e, "Failed to write history");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> ChatMessage {
+        ChatMessage {
+            header: "@all".to_string(),
+            sender: "alice".to_string(),
+            message: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn memory_history(limit: usize) -> History {
+        History::open(&HistoryConfig { limit, path: None }).unwrap()
+    }
+
+    fn texts(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.message.message.as_str()).collect()
+    }
+
+    #[test]
+    fn pages_go_backwards_oldest_first() {
+        let history = memory_history(10);
+        for text in ["one", "two", "three", "four"] {
+            let id = history.next_id();
+            history.record(id, &[Conversation::All], &message(text), Audience::default());
+        }
+
+        let latest = history.page(&Conversation::All, 2, None);
+        assert_eq!(texts(&latest), vec!["three", "four"]);
+        let older = history.page(&Conversation::All, 2, Some(latest[0].id));
+        assert_eq!(texts(&older), vec!["one", "two"]);
+        assert!(history.page(&Conversation::All, 2, Some(older[0].id)).is_empty());
+    }
+
+    #[test]
+    fn late_recorded_ids_still_page_in_order() {
+        let history = memory_history(10);
+        let ids: Vec<u64> = (0..3).map(|_| history.next_id()).collect();
+        // The middle SEND finishes delivering last
+        for (id, text) in [(ids[0], "one"), (ids[2], "three"), (ids[1], "two")] {
+            history.record(id, &[Conversation::All], &message(text), Audience::default());
+        }
+
+        let latest = history.page(&Conversation::All, 1, None);
+        assert_eq!(texts(&latest), vec!["three"]);
+        let older = history.page(&Conversation::All, 2, Some(latest[0].id));
+        assert_eq!(texts(&older), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn each_conversation_keeps_its_own_limit() {
+        let history = memory_history(2);
+        let room = Conversation::Room("general".to_string());
+        let id = history.next_id();
+        history.record(id, &[room.clone(), Conversation::direct("bob", "alice")], &message("both"), Audience::default());
+        for text in ["a", "b", "c"] {
+            let id = history.next_id();
+            history.record(id, &[Conversation::All], &message(text), Audience::default());
+        }
+
+        assert_eq!(texts(&history.page(&Conversation::All, 10, None)), vec!["b", "c"]);
+        assert_eq!(texts(&history.page(&room, 10, None)), vec!["both"]);
+        // Either participant finds the same private conversation
+        assert_eq!(texts(&history.page(&Conversation::direct("alice", "bob"), 10, None)), vec!["both"]);
+    }
+
+    fn audience(sender: Identity, recipients: &[Identity]) -> Audience {
+        Audience {
+            sender: Some(sender),
+            recipients: recipients.to_vec(),
+        }
+    }
+
+    #[test]
+    fn audience_lasts_as_long_as_the_message() {
+        let history = memory_history(1);
+        let room = Conversation::Room("general".to_string());
+        let sent = audience(Identity::Guest(1), &[Identity::Guest(2)]);
+        let id = history.next_id();
+        history.record(id, &[room.clone(), Conversation::All], &message("both"), sent.clone());
+
+        // Still held by the room after @all moves on
+        let newer = history.next_id();
+        history.record(newer, &[Conversation::All], &message("newer"), Audience::default());
+        assert_eq!(history.find(id).map(|(_, audience)| audience), Some(sent));
+
+        let newest = history.next_id();
+        history.record(newest, &[room], &message("newest"), Audience::default());
+        assert_eq!(history.find(id), None);
+        assert!(!history.state.lock().unwrap().held.contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn unlisted_messages_are_editable_but_not_saved() {
+        let path = std::env::temp_dir().join(format!("history-unlisted-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            limit: 10,
+            path: Some(path.clone()),
+        };
+
+        let history = History::open(&config).unwrap();
+        let id = history.next_id();
+        history.record(id, &[Conversation::Unlisted(3)], &message("psst"), Audience::default());
+        assert!(history.edit(id, "psst!", "2026-01-01T00:00:00.000Z"));
+        history.flush().await;
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("psst"));
+
+        // Only the ID survives a restart, so it is not handed out again
+        let reopened = History::open(&config).unwrap();
+        assert_eq!(reopened.find(id), None);
+        assert_eq!(reopened.next_id(), id + 1);
+        reopened.flush().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unlisted_messages_go_with_their_session() {
+        let history = memory_history(10);
+        let id = history.next_id();
+        history.record(id, &[Conversation::Unlisted(3)], &message("psst"), Audience::default());
+        assert!(history.find(id).is_some());
+
+        history.forget_unlisted(3);
+        assert_eq!(history.find(id), None);
+        assert!(history.state.lock().unwrap().held.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reloaded_audiences_keep_only_accounts() {
+        let path = std::env::temp_dir().join(format!("history-audience-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            limit: 10,
+            path: Some(path.clone()),
+        };
+
+        let history = History::open(&config).unwrap();
+        let account = Identity::Account("alice".to_string());
+        let id = history.next_id();
+        history.record(id, &[Conversation::All], &message("hi"), audience(account.clone(), &[Identity::Guest(4)]));
+        history.edit(id, "hello", "2026-01-01T00:00:00.000Z");
+        history.flush().await;
+
+        let reopened = History::open(&config).unwrap();
+        let (message, kept) = reopened.find(id).unwrap();
+        assert_eq!(message.message, "hello");
+        assert_eq!(kept, audience(account, &[]));
+        reopened.flush().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_deleted_ids_from_being_reused() {
+        let path = std::env::temp_dir().join(format!("history-compaction-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            limit: 10,
+            path: Some(path.clone()),
+        };
+
+        let history = History::open(&config).unwrap();
+        for text in ["one", "two", "three"] {
+            let id = history.next_id();
+            history.record(id, &[Conversation::All], &message(text), Audience::default());
+        }
+        history.edit(1, "uno", "2026-01-01T00:00:00.000Z");
+        assert!(history.delete(3));
+        history.flush().await;
+
+        // Each reopen compacts the file, so the second one reads what the first wrote
+        for _ in 0..2 {
+            let reopened = History::open(&config).unwrap();
+            assert_eq!(texts(&reopened.page(&Conversation::All, 10, None)), vec!["uno", "two"]);
+            assert_eq!(reopened.next_id(), 4);
+            reopened.flush().await;
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::IsTerminal;
+use tracing::Level;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+
+// Most to least severe; a level also shows everything above it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogLevel::Error => f.write_str("error"),
+            LogLevel::Warn => f.write_str("warn"),
+            LogLevel::Info => f.write_str("info"),
+            LogLevel::Debug => f.write_str("debug"),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, with the connection's span fields on every event
+    Json,
+}
+
+// Installs the global subscriber; RUST_LOG, if set, takes precedence over the configured level
+pub fn init(level: LogLevel, format: LogFormat) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::from(level).into())
+        .from_env_lossy();
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .with_ansi(std::io::stdout().is_terminal());
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_span_list(false).init(),
+    }
+}
+
+// Chat text for log fields: shown in full only while debug logging is on
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if tracing::enabled!(Level::DEBUG) {
+            f.write_str(self.0)
+        } else {
+            write!(f, "<{} chars>", self.0.chars().count())
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use homework4::protocol::ChatMessage;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tracing::warn;
+
+// Private messages held for one offline user before the oldest are dropped
+const MAILBOX_LIMIT: usize = 100;
+
+#[derive(Default)]
+struct MailboxState {
+    // Registered accounts; a guest name could be taken by someone else before its holder returns
+    known: HashSet<String>,
+    pending: HashMap<String, VecDeque<ChatMessage>>,
+}
+
+// Store-and-forward queues for private messages to users who are not connected
+#[derive(Default)]
+pub struct Mailbox {
+    state: Mutex<MailboxState>,
+}
+
+impl Mailbox {
+    pub fn remember(&self, username: &str) {
+        self.state.lock().unwrap().known.insert(username.to_string());
+    }
+
+    // Holds a message for an account; returns false for guests and names nobody has registered
+    pub fn queue(&self, username: &str, message: &ChatMessage) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.known.contains(username) {
+            return false;
+        }
+
+        let pending = state.pending.entry(username.to_string()).or_default();
+        if pending.len() >= MAILBOX_LIMIT {
+            pending.pop_front();
+            warn!(%username, "Mailbox full, dropped the oldest message");
+        }
+        pending.push_back(message.clone());
+        true
+    }
+
+    // Applies an EDIT to any copy still waiting for its recipient
+    pub fn edit(&self, id: u64, text: &str, edited_at: &str) {
+        let mut state = self.state.lock().unwrap();
+        for message in state.pending.values_mut().flatten().filter(|message| message.id == Some(id)) {
+            message.message = text.to_string();
+            message.edited_at = Some(edited_at.to_string());
+        }
+    }
+
+    // Drops a deleted message before its recipient ever sees it
+    pub fn delete(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        for pending in state.pending.values_mut() {
+            pending.retain(|message| message.id != Some(id));
+        }
+    }
+
+    // Everything waiting for a user, in the order it was sent
+    pub fn take(&self, username: &str) -> Vec<ChatMessage> {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .remove(username)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> ChatMessage {
+        ChatMessage {
+            header: "@bob".to_string(),
+            sender: "alice".to_string(),
+            message: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_remembered_accounts_get_mail() {
+        let mailbox = Mailbox::default();
+        assert!(!mailbox.queue("bob", &message("lost")));
+
+        mailbox.remember("bob");
+        assert!(mailbox.queue("bob", &message("first")));
+        assert!(mailbox.queue("bob", &message("second")));
+        let texts: Vec<String> = mailbox.take("bob").into_iter().map(|message| message.message).collect();
+        assert_eq!(texts, vec!["first", "second"]);
+        assert!(mailbox.take("bob").is_empty());
+    }
+
+    #[test]
+    fn full_mailbox_drops_the_oldest() {
+        let mailbox = Mailbox::default();
+        mailbox.remember("bob");
+        for n in 0..=MAILBOX_LIMIT {
+            mailbox.queue("bob", &message(&n.to_string()));
+        }
+        let pending = mailbox.take("bob");
+        assert_eq!(pending.len(), MAILBOX_LIMIT);
+        assert_eq!(pending[0].message, "1");
+    }
+}
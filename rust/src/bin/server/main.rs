@@ -0,0 +1,1193 @@
+mod accounts;
+mod api;
+mod config;
+mod history;
+mod logging;
+mod mailbox;
+mod metrics;
+mod moderation;
+mod outbound;
+mod receipts;
+mod session;
+mod tls;
+mod transport;
+
+use chrono::{SecondsFormat, Utc};
+use accounts::{AccountError, Accounts};
+use config::ServerConfig;
+use homework4::protocol::{
+    ChatMessage, Command, HistoryPage, MessageEdit, Password, PresenceEvent, PresenceKind, Receipt, ReceiptState,
+    Response, Status, TypingEvent, MIN_PASSWORD_LENGTH,
+};
+use history::{Audience, Conversation, History, HistoryConfig};
+use logging::{LogFormat, Redacted};
+use mailbox::Mailbox;
+use metrics::{DeliveryKind, Metrics};
+use moderation::{BanTarget, Moderation};
+use outbound::{Outbound, OutboundConfig};
+use receipts::{AckError, Receipts};
+use session::{ConnectionState, Identity, Registry, RoomError, SessionId};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tls::TlsConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use transport::{Line, LineReader, LineWriter};
+
+// How long a closing connection gets to flush its outbound queue
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a new connection gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What a listener speaks once any TLS handshake is done
+#[derive(Debug, Clone, Copy)]
+enum Gateway {
+    Lines,
+    WebSocket,
+}
+
+// Everything shared between connection handlers
+struct Server {
+    config: ServerConfig,
+    registry: Registry,
+    history: History,
+    mailbox: Mailbox,
+    accounts: Accounts,
+    // Whether JOIN without an account is allowed
+    allow_guests: bool,
+    outbound_config: OutboundConfig,
+    // Usernames that become admins when they LOGIN to their account
+    admins: HashSet<String>,
+    // Secret for the ADMIN command, for admins without a listed account
+    admin_token: Option<String>,
+    // Bearer token every REST API request must carry
+    api_token: Option<String>,
+    moderation: Moderation,
+    receipts: Receipts,
+    metrics: Metrics,
+    started: Instant,
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("[SERVER ERROR] {}", e);
+            std::process::exit(2);
+        }
+    };
+    logging::init(config.log_level, config.log_format);
+
+    // CHAT_ADMINS is a comma separated list of usernames allowed to use admin commands
+    let admins: HashSet<String> = std::env::var("CHAT_ADMINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|admin| admin.trim().to_string())
+        .filter(|admin| !admin.is_empty())
+        .collect();
+    let admin_token = std::env::var("CHAT_ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+    let api_token = std::env::var("CHAT_API_TOKEN").ok().filter(|token| !token.is_empty());
+    if config.api_port.is_some() && api_token.is_none() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "api_port is set but CHAT_API_TOKEN is not"));
+    }
+
+    let history_config = HistoryConfig::from_env();
+    let tls_config = TlsConfig::from_env();
+    let acceptor = tls_config.as_ref().map(TlsConfig::acceptor).transpose()?;
+
+    // CHAT_ACCOUNTS_FILE holds registered users; CHAT_ALLOW_GUESTS=1 lets anyone JOIN without one
+    let accounts_path = std::env::var_os("CHAT_ACCOUNTS_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("accounts.json"));
+    let accounts = Accounts::open(accounts_path)?;
+    let allow_guests = matches!(std::env::var("CHAT_ALLOW_GUESTS").as_deref(), Ok("1" | "true" | "yes"));
+
+    // Registered users can receive private messages before they first log in
+    let mailbox = Mailbox::default();
+    for username in accounts.usernames() {
+        mailbox.remember(&username);
+    }
+
+    let server = Arc::new(Server {
+        config,
+        registry: Registry::default(),
+        history: History::open(&history_config)?,
+        mailbox,
+        accounts,
+        allow_guests,
+        outbound_config: OutboundConfig::from_env(),
+        admins,
+        admin_token,
+        api_token,
+        moderation: Moderation::default(),
+        receipts: Receipts::default(),
+        metrics: Metrics::default(),
+        started: Instant::now(),
+    });
+
+    // Printed regardless of log level so every run records what it was started with
+    match server.config.log_format {
+        LogFormat::Text => match toml::to_string_pretty(&server.config) {
+            Ok(effective) => println!("Effective configuration:\n{}", effective.trim_end()),
+            Err(e) => error!("Could not print the configuration: {}", e),
+        },
+        LogFormat::Json => println!("{}", serde_json::json!({ "effective_config": &server.config })),
+    }
+
+    let mut listeners = Vec::new();
+    for ip in &server.config.bind {
+        let address = SocketAddr::new(*ip, server.config.port);
+        listeners.push((TcpListener::bind(address).await?, Gateway::Lines));
+        info!(%address, "Server listening");
+    }
+    if let Some(port) = server.config.websocket_port {
+        for ip in &server.config.bind {
+            let address = SocketAddr::new(*ip, port);
+            listeners.push((TcpListener::bind(address).await?, Gateway::WebSocket));
+            info!(%address, "WebSocket gateway listening");
+        }
+    }
+    // Metrics are only served on loopback; put a proxy in front to expose them further
+    let metrics_listener = match server.config.metrics_port {
+        Some(port) => {
+            let address = SocketAddr::from(([127, 0, 0, 1], port));
+            let listener = TcpListener::bind(address).await?;
+            info!(%address, "Serving metrics at /metrics");
+            Some(listener)
+        }
+        None => None,
+    };
+    // Like metrics, the API is loopback only
+    let api_listener = match server.config.api_port {
+        Some(port) => {
+            let address = SocketAddr::from(([127, 0, 0, 1], port));
+            let listener = TcpListener::bind(address).await?;
+            info!(%address, sender = %server.config.api_sender, "Serving the REST API");
+            Some(listener)
+        }
+        None => None,
+    };
+    match &tls_config {
+        Some(tls) => info!("TLS enabled with certificate {}", tls.cert_path.display()),
+        None => info!("TLS disabled, traffic is plaintext"),
+    }
+    info!(
+        "Outbound queues hold {} messages, overflow policy is {}",
+        server.outbound_config.capacity, server.outbound_config.policy
+    );
+    info!(
+        "{} admin username(s) configured, ADMIN token is {}",
+        server.admins.len(),
+        if server.admin_token.is_some() { "set" } else { "not set" }
+    );
+    info!(
+        "{} registered account(s) in {}, guests are {}",
+        server.accounts.usernames().len(),
+        server.accounts.path().display(),
+        if server.allow_guests { "allowed" } else { "not allowed" }
+    );
+    match &history_config.path {
+        Some(path) => info!("Keeping {} messages per conversation in {}", history_config.limit, path.display()),
+        None => info!("Keeping {} messages per conversation in memory only", history_config.limit),
+    }
+
+    // Every accept loop and connection task holds a sender; recv() returns None once they have all finished
+    let (running, mut finished) = mpsc::channel::<()>(1);
+    let mut accept_tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, gateway)| {
+            tokio::spawn(accept_connections(listener, gateway, acceptor.clone(), Arc::clone(&server), running.clone()))
+        })
+        .collect();
+    if let Some(listener) = metrics_listener {
+        accept_tasks.push(tokio::spawn(metrics::serve(listener, Arc::clone(&server))));
+    }
+    if let Some(listener) = api_listener {
+        accept_tasks.push(tokio::spawn(api::serve(listener, Arc::clone(&server))));
+    }
+
+    let signal = shutdown_signal().await;
+    let stopping = Instant::now();
+    info!(signal, "Shutting down");
+    for task in &accept_tasks {
+        task.abort();
+    }
+    drop(running);
+
+    let sessions = server.registry.shut_down();
+    let notice = Response::Shutdown(server.config.shutdown_reason.clone()).encode();
+    let mut notified = 0;
+    for (outbound, joined) in &sessions {
+        if *joined && outbound.push(notice.clone()) {
+            notified += 1;
+        }
+        // Closing ends the connection's read loop; its writer still drains what is queued
+        outbound.close();
+    }
+
+    let _ = finished.recv().await;
+    server.history.flush().await;
+    info!(
+        "Shut down after {}s of uptime: notified {} joined session(s) and closed {} connection(s) in {}ms",
+        server.started.elapsed().as_secs(),
+        notified,
+        sessions.len(),
+        stopping.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+// Resolves with the name of the first SIGINT or SIGTERM
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        let mut terminate = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("Could not listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return "SIGINT";
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    gateway: Gateway,
+    acceptor: Option<TlsAcceptor>,
+    server: Arc<Server>,
+    running: mpsc::Sender<()>,
+) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let server_clone = Arc::clone(&server);
+        let acceptor = acceptor.clone();
+        let running = running.clone();
+        // Every event for this connection carries the peer, and the username once it has joined
+        let span = info_span!("connection", peer = %peer_addr, username = tracing::field::Empty);
+
+        tokio::spawn(
+            async move {
+                let _running = running;
+                let Some(acceptor) = acceptor else {
+                    let _ = serve(stream, gateway, peer_addr, server_clone).await;
+                    return;
+                };
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = serve(stream, gateway, peer_addr, server_clone).await;
+                    }
+                    Ok(Err(e)) => warn!(error = %e, "TLS handshake failed"),
+                    Err(_) => warn!("TLS handshake timed out"),
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+// Speaks the listener's protocol over an accepted stream, already decrypted if TLS is on
+async fn serve<S>(stream: S, gateway: Gateway, peer_addr: SocketAddr, server: Arc<Server>) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let max_line_length = server.config.messages.max_line_length;
+    match gateway {
+        Gateway::Lines => {
+            let (reader, writer) = transport::split_stream(stream, max_line_length);
+            handle_client(reader, writer, peer_addr, server).await
+        }
+        Gateway::WebSocket => {
+            // Slightly long frames get 400 LINE TOO LONG; far longer ones are refused before being buffered
+            let frame_limit = Some(max_line_length * 4);
+            let config = WebSocketConfig::default().max_message_size(frame_limit).max_frame_size(frame_limit);
+            let handshake = tokio_tungstenite::accept_async_with_config(stream, Some(config));
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!(error = %e, "WebSocket handshake failed");
+                    return Ok(());
+                }
+                Err(_) => {
+                    warn!("WebSocket handshake timed out");
+                    return Ok(());
+                }
+            };
+            let (reader, writer) = transport::split_websocket(stream, max_line_length);
+            handle_client(reader, writer, peer_addr, server).await
+        }
+    }
+}
+
+// Serves one connection, line protocol or WebSocket, until it disconnects or leaves
+async fn handle_client<R, W>(mut reader: R, writer: W, peer_addr: SocketAddr, server: Arc<Server>) -> std::io::Result<()>
+where
+    R: LineReader,
+    W: LineWriter + Send + 'static,
+{
+    info!("New connection");
+    server.metrics.connection_opened();
+
+    let outbound = Arc::new(Outbound::new(peer_addr.to_string(), server.outbound_config));
+    let mut writer_task = tokio::spawn(write_outbound(writer, Arc::clone(&outbound)).in_current_span());
+    //outbound.push(Response::Testing.encode());
+
+    let registered = if server.moderation.is_banned(&BanTarget::Ip(peer_addr.ip())) {
+        info!("Refusing banned address");
+        outbound.push(Response::Banned.encode());
+        None
+    } else {
+        let registered = server.registry.register(peer_addr.ip(), Arc::clone(&outbound));
+        if registered.is_none() {
+            info!("Refusing connection while shutting down");
+        }
+        registered
+    };
+    let Some(session_id) = registered else {
+        outbound.close();
+        let _ = writer_task.await;
+        server.metrics.connection_closed();
+        return Ok(());
+    };
+
+    let result = read_commands(&mut reader, session_id, &outbound, &server).await;
+    cleanup_user(session_id, &server);
+
+    outbound.close();
+    if tokio::time::timeout(FLUSH_TIMEOUT, &mut writer_task).await.is_err() {
+        error!("Timed out flushing outbound messages");
+        writer_task.abort();
+    }
+    server.metrics.connection_closed();
+
+    result
+}
+
+async fn read_commands(
+    reader: &mut impl LineReader,
+    session_id: SessionId,
+    outbound: &Outbound,
+    server: &Server,
+) -> std::io::Result<()> {
+    let max_line_length = server.config.messages.max_line_length;
+
+    let timeouts = &server.config.timeouts;
+    let mut last_received = Instant::now();
+    // Only real commands count; heartbeats alone do not keep an idle connection open
+    let mut last_command = Instant::now();
+    let mut ping_sent: Option<Instant> = None;
+    let mut pings: u64 = 0;
+
+    loop {
+        let (heartbeat_deadline, idle_deadline) = timeouts.deadlines(last_received, last_command, ping_sent);
+
+        let lines = tokio::select! {
+            result = reader.read_lines() => match result {
+                Ok(None) => {
+                    info!("Client disconnected");
+                    return Ok(());
+                }
+                Ok(Some(lines)) => lines,
+                Err(e) => {
+                    error!(error = %e, "Error reading from client");
+                    return Err(e);
+                }
+            },
+            _ = outbound.closed() => {
+                info!("Closing connection");
+                return Ok(());
+            }
+            _ = tokio::time::sleep_until(heartbeat_deadline) => {
+                if ping_sent.is_some() {
+                    warn!(timeout_secs = timeouts.heartbeat_timeout, "No reply to PING, disconnecting");
+                    return Ok(());
+                }
+                pings += 1;
+                outbound.push(Response::Ping(Some(pings.to_string())).encode());
+                ping_sent = Some(Instant::now());
+                continue;
+            }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or(heartbeat_deadline)), if idle_deadline.is_some() => {
+                info!(idle_secs = timeouts.idle, "No commands for too long, disconnecting");
+                outbound.push(Response::IdleTimeout.encode());
+                return Ok(());
+            }
+        };
+
+        // Any traffic at all shows the connection is still alive
+        last_received = Instant::now();
+        ping_sent = None;
+
+        for line in lines {
+            match line {
+                Line::Complete(raw_message) => {
+                    if handle_command(&raw_message, session_id, outbound, server) {
+                        last_command = Instant::now();
+                    }
+                }
+                Line::TooLong => {
+                    warn!(max_line_length, "Line too long");
+                    outbound.push(Response::LineTooLong.encode());
+                }
+            }
+
+            if server.registry.state(session_id) == Some(ConnectionState::Leaving) {
+                info!("Client left, closing connection");
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Drains one client's outbound queue onto its socket
+async fn write_outbound(mut writer: impl LineWriter, outbound: Arc<Outbound>) {
+    while let Some(line) = outbound.pop().await {
+        if let Err(e) = writer.write_line(&line).await {
+            error!(error = %e, "Failed to write to client");
+            outbound.close();
+            return;
+        }
+    }
+    writer.shutdown().await;
+}
+
+// Runs one command line and queues its response, plus anything that must follow it, on the caller's outbound.
+// Returns false for PING and PONG, which do not reset the idle timeout.
+fn handle_command(
+    raw_message: &str,
+    session_id: SessionId,
+    outbound: &Outbound,
+    server: &Server,
+) -> bool {
+    let registry = &server.registry;
+
+    let command = match Command::parse(raw_message) {
+        Ok(command) => command,
+        Err(e) => {
+            warn!(error = %e, "Could not parse command");
+            outbound.push(e.response().encode());
+            return true;
+        }
+    };
+    // Logged after parsing so passwords are redacted
+    debug!(?command, "Received command");
+
+    let connection_state = registry.state(session_id).unwrap_or(ConnectionState::Leaving);
+    match (connection_state, &command) {
+        (_, Command::Ping(_) | Command::Pong(_)) => {}
+        (ConnectionState::Connected, Command::Join(_) | Command::Login { .. } | Command::Register { .. }) => {}
+        (_, Command::Join(_) | Command::Login { .. }) => {
+            warn!("Tried to join twice");
+            outbound.push(Response::AlreadyJoined.encode());
+            return true;
+        }
+        (ConnectionState::Joined, _) => {}
+        _ => {
+            warn!("Sent a command before joining");
+            outbound.push(Response::NotJoined.encode());
+            return true;
+        }
+    }
+
+    let mut joined = None;
+    let response = match command {
+        Command::Ping(token) => {
+            outbound.push(Response::Pong(token).encode());
+            return false;
+        }
+        Command::Pong(_) => return false,
+        Command::Typing(header) => {
+            typing(session_id, header, server);
+            return true;
+        }
+        Command::Join(username) => {
+            if !server.allow_guests {
+                info!(%username, "Guest join refused");
+                Response::LoginRequired
+            } else if server.moderation.is_banned(&BanTarget::Username(username.clone())) {
+                info!(%username, "Banned username refused");
+                Response::Banned
+            } else if server.config.usernames.allows(&username)
+                && !server.accounts.exists(&username)
+                && registry.join(session_id, &username, false)
+            {
+                Span::current().record("username", username.as_str());
+                info!("Joined as a guest");
+                complete_join(session_id, &username, server);
+                joined = Some(username);
+                Response::Ok
+            } else {
+                info!(%username, "Invalid username");
+                Response::InvalidUsername
+            }
+        }
+        Command::Register { username, password } => register(session_id, &username, &password, server),
+        Command::Login { username, .. } if server.moderation.is_banned(&BanTarget::Username(username.clone())) => {
+            info!(%username, "Banned username refused");
+            Response::Banned
+        }
+        Command::Login { username, password } => {
+            // Argon2 is deliberately slow, so keep it off the other tasks on this worker
+            let verified = tokio::task::block_in_place(|| server.accounts.verify(&username, &password.0));
+            if !verified {
+                info!(%username, "Failed login");
+                Response::InvalidCredentials
+            } else if registry.join(session_id, &username, true) {
+                Span::current().record("username", username.as_str());
+                info!("Logged in");
+                // Only a real login grants a listed admin name, since a guest could pick any unregistered name
+                if server.admins.contains(&username) {
+                    registry.set_admin(session_id);
+                }
+                complete_join(session_id, &username, server);
+                joined = Some(username);
+                Response::Ok
+            } else {
+                info!(%username, "Already logged in elsewhere, refused");
+                Response::AlreadyLoggedIn
+            }
+        }
+        Command::Leave => {
+            if let Some(username) = registry.leave(session_id) {
+                broadcast_presence(registry, PresenceKind::Leave, &username, Status::Offline, Some(session_id));
+            }
+            Response::Bye
+        }
+        Command::Send(message) => send_message(message, session_id, server),
+        Command::Ack { id, state } => acknowledge(session_id, id, state, server),
+        Command::Edit { id, message } => change_message(session_id, id, Some(message), server),
+        Command::Delete(id) => change_message(session_id, id, None, server),
+        Command::UserBoard => {
+            debug!("User is requesting the userboard");
+            user_board(registry)
+        }
+        Command::UserStatus { username, status } => {
+            debug!("User is requesting to change their status");
+            user_status_update(session_id, username.as_deref(), status, server)
+        }
+        Command::JoinRoom(room) => join_room(session_id, &room, registry),
+        Command::PartRoom(room) => part_room(session_id, &room, registry),
+        Command::Rooms => {
+            debug!("User is requesting the room list");
+            Response::Rooms(registry.rooms())
+        }
+        Command::History { target, count, before } => {
+            debug!(%target, "User is requesting history");
+            history(session_id, target, count, before, server)
+        }
+        Command::Admin(token) => {
+            if server.admin_token.as_deref() == Some(token.0.as_str()) {
+                info!("Granted admin by token");
+                registry.set_admin(session_id);
+                Response::AdminGranted
+            } else {
+                warn!("Wrong ADMIN token");
+                Response::InvalidCredentials
+            }
+        }
+        command @ (Command::Kick { .. }
+        | Command::Ban { .. }
+        | Command::Unban(_)
+        | Command::Mute { .. }
+        | Command::Unmute(_)) => {
+            if registry.is_admin(session_id) {
+                moderate(command, session_id, server)
+            } else {
+                warn!("Moderation command from a non-admin");
+                Response::NotAdmin
+            }
+        }
+    };
+
+    outbound.push(response.encode());
+
+    // Private messages that arrived while this user was away follow the 200 OK
+    if let Some(username) = joined {
+        let pending = server.mailbox.take(&username);
+        if !pending.is_empty() {
+            info!(count = pending.len(), "Delivering queued messages");
+        }
+        for message in &pending {
+            send_to_user(outbound, message, DeliveryKind::Direct, &server.metrics);
+        }
+    }
+    true
+}
+
+fn send_message(mut message: ChatMessage, session_id: SessionId, server: &Server) -> Response {
+    let registry = &server.registry;
+    // The sender is whoever this connection joined as, not whatever the client claims
+    let Some(username) = registry.username(session_id) else {
+        return Response::NotJoined;
+    };
+    if server.moderation.is_muted(&username) {
+        return Response::Muted;
+    }
+    if message.sender != username {
+        debug!(claimed = %message.sender, "Replacing claimed sender");
+        message.sender = username;
+    }
+    route_message(message, Some(session_id), server)
+}
+
+// Records a recipient's ACK and tells the sender, if they are online and this is news to them
+fn acknowledge(session_id: SessionId, id: u64, state: ReceiptState, server: &Server) -> Response {
+    let (Some(username), Some(identity)) = (server.registry.username(session_id), server.registry.identity(session_id))
+    else {
+        return Response::NotJoined;
+    };
+    match server.receipts.acknowledge(id, &identity, state) {
+        Ok(Some(sender)) => {
+            debug!(id, %state, "Message acknowledged");
+            if let Some((_, sender_stream)) = server.registry.outbound_for_identity(&sender) {
+                sender_stream.push(Response::Receipt(Receipt { id, username, state }).encode());
+            }
+            Response::Acked
+        }
+        Ok(None) => Response::Acked,
+        Err(AckError::NoSuchMessage) => {
+            debug!(id, "ACK for an unknown message");
+            Response::NoSuchMessage
+        }
+    }
+}
+
+// EDIT with the new text, or DELETE without; only the original sender or an admin may change a message
+fn change_message(session_id: SessionId, id: u64, text: Option<String>, server: &Server) -> Response {
+    let Some(username) = server.registry.username(session_id) else {
+        return Response::NotJoined;
+    };
+    let Some((original, audience)) = server.history.find(id) else {
+        debug!(id, "EDIT or DELETE of an unknown message");
+        return Response::NoSuchMessage;
+    };
+    // Matched on the sender's account or guest session, so a later guest with the same name cannot
+    let is_sender = audience.sender.is_some() && audience.sender == server.registry.identity(session_id);
+    if !is_sender && !server.registry.is_admin(session_id) {
+        warn!(id, sender = %original.sender, "Tried to change someone else's message");
+        return Response::NotYourMessage;
+    }
+
+    let (event, reply) = match text {
+        Some(text) => {
+            // Editing is saying something new, so a mute applies to it like it does to SEND
+            if server.moderation.is_muted(&username) {
+                return Response::Muted;
+            }
+            let content_length = text.trim().chars().count();
+            if !server.config.messages.allows(content_length) {
+                warn!(length = content_length, "Edited message length out of range");
+                return Response::MessageFailed;
+            }
+            let edited_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+            if !server.history.edit(id, &text, &edited_at) {
+                return Response::NoSuchMessage;
+            }
+            server.mailbox.edit(id, &text, &edited_at);
+            info!(id, body = %Redacted(&text), "Message edited");
+            let edit = MessageEdit {
+                id,
+                message: text,
+                edited_at,
+            };
+            (Response::Edited(edit), Response::MessageEdited)
+        }
+        None => {
+            if !server.history.delete(id) {
+                return Response::NoSuchMessage;
+            }
+            server.mailbox.delete(id);
+            info!(id, "Message deleted");
+            (Response::Deleted(id), Response::MessageDeleted)
+        }
+    };
+
+    notify_audience(&audience, &event, session_id, server);
+    reply
+}
+
+// Sends an EDITED or DELETED event to the sender and to the recipients the message reached when it was sent
+fn notify_audience(audience: &Audience, event: &Response, exclude: SessionId, server: &Server) {
+    let registry = &server.registry;
+    let audience = audience
+        .recipients
+        .iter()
+        .chain(&audience.sender)
+        .filter_map(|identity| registry.outbound_for_identity(identity));
+
+    let line = event.encode();
+    let mut notified = HashSet::from([exclude]);
+    for (id, stream) in audience {
+        if notified.insert(id) && !stream.push(line.clone()) {
+            error!(session = id, "Failed to queue message change");
+        }
+    }
+}
+
+// Relays a typing indicator to whoever a SEND with this header would reach; dropped quietly if it cannot be
+fn typing(session_id: SessionId, header: String, server: &Server) {
+    let registry = &server.registry;
+    let Some(username) = registry.username(session_id) else {
+        return;
+    };
+    if server.moderation.is_muted(&username) {
+        return;
+    }
+    if !registry.typing_allowed(session_id, server.config.timeouts.typing_interval()) {
+        debug!("Typing indicator rate limited");
+        return;
+    }
+
+    let mut audience = Vec::new();
+    if header.trim() == "@all" {
+        audience = registry.joined_outbounds();
+    } else {
+        for word in header.split_whitespace() {
+            if let Some(room) = word.strip_prefix('#') {
+                // Like SEND, only members can reach a room
+                audience.extend(registry.room_outbounds(session_id, room).unwrap_or_default());
+            } else if let Some(recipient) = word.strip_prefix('@') {
+                audience.extend(registry.outbound_for(recipient));
+            }
+        }
+    }
+
+    let event = Response::Typing(TypingEvent {
+        username,
+        header,
+        expires_in: server.config.timeouts.typing,
+    });
+    let line = event.encode();
+    let mut notified = HashSet::from([session_id]);
+    for (id, stream) in audience {
+        if notified.insert(id) {
+            stream.push(line.clone());
+        }
+    }
+}
+
+// Checks and delivers a message whose sender is already settled; `from` is the sending session, if there is one
+fn route_message(mut message: ChatMessage, from: Option<SessionId>, server: &Server) -> Response {
+    let registry = &server.registry;
+    let content_length = message.message.trim().chars().count();
+    if !server.config.messages.allows(content_length) {
+        warn!(length = content_length, "Message length out of range");
+        return Response::MessageFailed;
+    }
+
+    // Stamped here so every recipient, the history and the sender's reply agree
+    let message_id = server.history.next_id();
+    message.id = Some(message_id);
+    message.sent_at = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+    message.edited_at = None;
+    info!(id = message_id, header = %message.header, body = %Redacted(&message.message), "Relaying message");
+    server.metrics.message_relayed();
+
+    // Receipts go back to whoever sent this, not to a later holder of the same name
+    let sender = from.and_then(|id| registry.identity(id));
+    if message.is_broadcast() {
+        broadcast_message(server, &message, from);
+        let mut recipients = registry.joined_identities();
+        recipients.retain(|recipient| Some(recipient) != sender.as_ref());
+        server.receipts.track(message_id, sender.clone(), recipients.clone());
+        server.history.record(message_id, &[Conversation::All], &message, Audience { sender, recipients });
+        return Response::Sent(message_id);
+    }
+
+    let rooms = message.rooms();
+    let recipients = message.recipients();
+    if rooms.is_empty() && recipients.is_empty() {
+        return Response::MessageFailed;
+    }
+
+    // Resolve every room before delivering anything so a bad #room sends nothing
+    let mut room_targets = Vec::new();
+    let mut conversations = Vec::new();
+    for room in rooms {
+        // Only members can send to a room, so a message from outside any session cannot
+        let members = match from {
+            Some(session_id) => registry.room_outbounds(session_id, room),
+            None => Err(RoomError::NotInRoom),
+        };
+        match members {
+            Ok(members) => {
+                room_targets.extend(members);
+                conversations.push(Conversation::Room(room.to_string()));
+            }
+            Err(e) => {
+                warn!(%room, error = ?e, "Cannot send to room");
+                return room_error_response(e);
+            }
+        }
+    }
+
+    // Someone named in the header and also in a target room only gets one copy
+    let mut delivered: HashSet<SessionId> = from.into_iter().collect();
+    // Everyone who can acknowledge this message
+    let mut reached = HashSet::new();
+    let mut all_sent = true;
+    let mut queued = false;
+    for (id, stream) in room_targets {
+        if !delivered.insert(id) {
+            continue;
+        }
+        if send_to_user(&stream, &message, DeliveryKind::Room, &server.metrics) {
+            reached.extend(registry.identity(id));
+        } else {
+            error!(session = id, "Failed to queue message");
+            all_sent = false;
+        }
+    }
+
+    for recipient in recipients {
+        debug!(%recipient, "Finding recipient");
+
+        if let Some((id, user_stream)) = registry.outbound_for(recipient) {
+            // Still part of the private conversation even if a room already delivered it
+            let conversation = private_conversation(&message.sender, sender.as_ref(), recipient, Some(id), server);
+            conversations.extend(conversation.filter(|conversation| !conversations.contains(conversation)));
+            if !delivered.insert(id) {
+                continue;
+            }
+            if !send_to_user(&user_stream, &message, DeliveryKind::Direct, &server.metrics) {
+                error!(%recipient, "Failed to queue message");
+                all_sent = false;
+            } else {
+                debug!(%recipient, "Message delivered");
+                reached.extend(registry.identity(id));
+            }
+        } else if server.mailbox.queue(recipient, &message) {
+            info!(%recipient, "Recipient offline, queued for their next login");
+            let conversation = private_conversation(&message.sender, sender.as_ref(), recipient, None, server);
+            conversations.extend(conversation.filter(|conversation| !conversations.contains(conversation)));
+            // Only accounts have a mailbox
+            reached.insert(Identity::Account(recipient.to_string()));
+            queued = true;
+        } else {
+            warn!(%recipient, "No such recipient");
+            all_sent = false;
+        }
+    }
+
+    // A message delivered to someone before another recipient failed can still be acknowledged
+    if let Some(sender) = &sender {
+        reached.remove(sender);
+    }
+    let recipients: Vec<Identity> = reached.into_iter().collect();
+    server.receipts.track(message_id, sender.clone(), recipients.clone());
+    if !conversations.is_empty() {
+        server.history.record(message_id, &conversations, &message, Audience { sender, recipients });
+    }
+
+    if !all_sent {
+        Response::MessageFailed
+    } else if queued {
+        Response::Queued(message_id)
+    } else {
+        Response::Sent(message_id)
+    }
+}
+
+fn history(session_id: SessionId, target: String, count: usize, before: Option<u64>, server: &Server) -> Response {
+    let Some(username) = server.registry.username(session_id) else {
+        return Response::NotJoined;
+    };
+
+    let conversation = if target == "@all" {
+        Conversation::All
+    } else if let Some(room) = target.strip_prefix('#') {
+        // Room history is only readable from inside the room
+        if let Err(e) = server.registry.check_room_member(session_id, room) {
+            return room_error_response(e);
+        }
+        Conversation::Room(room.to_string())
+    } else if let Some(other) = target.strip_prefix('@') {
+        match direct_conversation(&username, other, server) {
+            Some(conversation) => conversation,
+            // Nothing is kept for a guest name, since its next holder could be someone else
+            None => {
+                return Response::History(HistoryPage {
+                    target,
+                    messages: Vec::new(),
+                })
+            }
+        }
+    } else {
+        return Response::InvalidRequest;
+    };
+
+    let count = count.clamp(1, server.history.limit());
+    let messages = server.history.page(&conversation, count, before);
+    Response::History(HistoryPage { target, messages })
+}
+
+// Private history is only kept between names that always mean the same person: accounts, and reserved
+// names such as the API sender that nobody can join as. Anyone could take a guest name after its holder leaves.
+fn direct_conversation(a: &str, b: &str, server: &Server) -> Option<Conversation> {
+    let lasting = |username: &str| {
+        server.accounts.exists(username)
+            || server.config.usernames.reserved.iter().any(|reserved| reserved.eq_ignore_ascii_case(username))
+    };
+    (lasting(a) && lasting(b)).then(|| Conversation::direct(a, b))
+}
+
+// Where a private message is kept: between two lasting names, its pageable conversation; otherwise unlisted with
+// the guest's session, the sender's if both are guests, so it can be edited but never read back by a later guest
+fn private_conversation(
+    sender_name: &str,
+    sender: Option<&Identity>,
+    recipient: &str,
+    recipient_session: Option<SessionId>,
+    server: &Server,
+) -> Option<Conversation> {
+    if let Some(conversation) = direct_conversation(sender_name, recipient, server) {
+        return Some(conversation);
+    }
+    match sender {
+        Some(Identity::Guest(session)) => Some(Conversation::Unlisted(*session)),
+        _ => recipient_session.map(Conversation::Unlisted),
+    }
+}
+
+// Tells everyone else about a session that has just bound its username
+fn complete_join(session_id: SessionId, username: &str, server: &Server) {
+    broadcast_presence(&server.registry, PresenceKind::Join, username, Status::Online, Some(session_id));
+}
+
+fn register(session_id: SessionId, username: &str, password: &Password, server: &Server) -> Response {
+    if !server.config.usernames.allows(username) {
+        return Response::InvalidUsername;
+    }
+    if password.0.chars().count() < MIN_PASSWORD_LENGTH {
+        return Response::InvalidPassword;
+    }
+    // A guest may claim their own name, but not one somebody else is using right now
+    if let Some((owner, _)) = server.registry.outbound_for(username) {
+        if owner != session_id {
+            return Response::InvalidUsername;
+        }
+    }
+    // Logging in to a listed admin name grants admin, so only an existing admin may create that account
+    if server.admins.contains(username) && !server.registry.is_admin(session_id) {
+        warn!(%username, "Tried to register a listed admin name");
+        return Response::NotAdmin;
+    }
+
+    match tokio::task::block_in_place(|| server.accounts.register(username, &password.0)) {
+        Ok(()) => {
+            info!(%username, "Registered account");
+            server.mailbox.remember(username);
+            Response::Registered
+        }
+        Err(AccountError::Exists) => Response::AccountExists,
+        Err(AccountError::Io(e)) => {
+            error!(%username, error = %e, "Could not save account");
+            Response::ServerError
+        }
+    }
+}
+
+fn is_valid_room_name(room: &str) -> bool {
+    (1..=30).contains(&room.len()) && room.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+fn join_room(session_id: SessionId, room: &str, registry: &Registry) -> Response {
+    if !is_valid_room_name(room) {
+        return Response::InvalidRoomName;
+    }
+    if registry.join_room(session_id, room) {
+        info!(%room, "Joined room");
+        Response::RoomJoined(room.to_string())
+    } else {
+        Response::NotJoined
+    }
+}
+
+fn part_room(session_id: SessionId, room: &str, registry: &Registry) -> Response {
+    match registry.part_room(session_id, room) {
+        Ok(()) => {
+            info!(%room, "Left room");
+            Response::RoomLeft(room.to_string())
+        }
+        Err(e) => room_error_response(e),
+    }
+}
+
+fn room_error_response(error: RoomError) -> Response {
+    match error {
+        RoomError::NoSuchRoom => Response::NoSuchRoom,
+        RoomError::NotInRoom => Response::NotInRoom,
+    }
+}
+
+fn cleanup_user(session_id: SessionId, server: &Server) {
+    server.history.forget_unlisted(session_id);
+    if let Some(session) = server.registry.remove(session_id) {
+        // Sessions that sent LEAVE were already announced
+        if let (ConnectionState::Joined, Some(username)) = (session.state, &session.username) {
+            broadcast_presence(&server.registry, PresenceKind::Leave, username, Status::Offline, None);
+        }
+
+        let connected_for = Utc::now() - session.connected_at;
+        server.metrics.session_cleaned_up(connected_for.num_seconds().max(0) as u64);
+        info!(session = session.id, connected_secs = connected_for.num_seconds(), "Cleaned up session");
+    }
+}
+
+// Runs KICK, BAN, UNBAN, MUTE or UNMUTE for a caller already known to be an admin
+fn moderate(command: Command, session_id: SessionId, server: &Server) -> Response {
+    let registry = &server.registry;
+    match command {
+        Command::Kick { username, reason } => match registry.outbound_for(&username) {
+            Some((_, outbound)) => {
+                info!(%username, "Kicked user");
+                kick(&outbound, reason);
+                Response::UserKicked
+            }
+            None => Response::NoSuchUser,
+        },
+        Command::Ban { target, seconds, reason } => {
+            let target = BanTarget::parse(&target);
+            // Whoever the ban covers is disconnected straight away, except the admin who issued it
+            let mut connected = match &target {
+                BanTarget::Username(username) => registry.outbound_for(username).into_iter().collect(),
+                BanTarget::Ip(ip) => registry.outbounds_from(*ip),
+            };
+            connected.retain(|(id, _)| *id != session_id);
+            if !server.moderation.ban(target.clone(), Duration::from_secs(seconds)) {
+                warn!(%target, seconds, "Ban duration too long");
+                return Response::InvalidRequest;
+            }
+            info!(%target, seconds, disconnected = connected.len(), "Added ban");
+            for (_, outbound) in &connected {
+                kick(outbound, reason.clone());
+            }
+            Response::UserBanned
+        }
+        Command::Unban(target) => {
+            let target = BanTarget::parse(&target);
+            if server.moderation.unban(&target) {
+                info!(%target, "Lifted ban");
+                Response::UserUnbanned
+            } else {
+                Response::NoSuchUser
+            }
+        }
+        Command::Mute { username, seconds } => {
+            if !server.moderation.mute(&username, Duration::from_secs(seconds)) {
+                warn!(%username, seconds, "Mute duration too long");
+                return Response::InvalidRequest;
+            }
+            info!(%username, seconds, "Muted user");
+            Response::UserMuted
+        }
+        Command::Unmute(username) => {
+            if server.moderation.unmute(&username) {
+                info!(%username, "Unmuted user");
+                Response::UserUnmuted
+            } else {
+                Response::NoSuchUser
+            }
+        }
+        _ => Response::InvalidRequest,
+    }
+}
+
+// Tells a user why they are going, then closes their connection; cleanup announces the LEAVE
+fn kick(outbound: &Outbound, reason: Option<String>) {
+    outbound.push(Response::Kicked(reason).encode());
+    outbound.close();
+}
+
+fn user_board(registry: &Registry) -> Response {
+    Response::Board(registry.board())
+}
+
+fn user_status_update(session_id: SessionId, target: Option<&str>, new_status: Status, server: &Server) -> Response {
+    let Some(username) = server.registry.username(session_id) else {
+        return Response::NotJoined;
+    };
+
+    // Anyone may change their own status; changing someone else's needs an admin
+    let target = target.unwrap_or(&username);
+    if target != username && !server.registry.is_admin(session_id) {
+        warn!(%target, "Tried to change someone else's status");
+        return Response::NotYourUser;
+    }
+
+    if server.registry.set_status(target, new_status) {
+        info!(%target, status = %new_status, "Status changed");
+        broadcast_presence(&server.registry, PresenceKind::Status, target, new_status, None);
+        Response::StatusUpdated
+    } else {
+        Response::InvalidRequest
+    }
+}
+
+fn broadcast_message(server: &Server, message: &ChatMessage, exclude: Option<SessionId>) {
+    let (delivered, failed) = broadcast(&server.registry, &Response::Message(message.clone()), exclude);
+    server.metrics.broadcast(delivered, failed);
+}
+
+fn broadcast_presence(registry: &Registry, event: PresenceKind, username: &str, status: Status, exclude: Option<SessionId>) {
+    let event = PresenceEvent {
+        event,
+        username: username.to_string(),
+        status,
+    };
+    broadcast(registry, &Response::Presence(event), exclude);
+}
+
+// Queues a line for every joined session except `exclude`; returns how many were queued and how many failed
+fn broadcast(registry: &Registry, response: &Response, exclude: Option<SessionId>) -> (u64, u64) {
+    let message_string = response.encode();
+    debug!(line = message_string.trim_end(), "Broadcasting");
+
+    let (mut delivered, mut failed) = (0, 0);
+    for (id, stream) in registry.joined_outbounds() {
+        if Some(id) == exclude {
+            continue;
+        }
+        if stream.push(message_string.clone()) {
+            delivered += 1;
+        } else {
+            error!(session = id, "Failed to queue message");
+            failed += 1;
+        }
+    }
+    (delivered, failed)
+}
+
+fn send_to_user(stream: &Outbound, message: &ChatMessage, kind: DeliveryKind, metrics: &Metrics) -> bool {
+    let json_string = Response::Message(message.clone()).encode();
+    debug!(line = json_string.trim_end(), ?kind, "Sending message");
+    let delivered = stream.push(json_string);
+    metrics.delivery(kind, delivered);
+    delivered
+}
@@ -0,0 +1,181 @@
+use crate::Server;
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::error;
+
+// Which fan-out a single delivery belongs to, for the `kind` label
+#[derive(Debug, Clone, Copy)]
+pub enum DeliveryKind {
+    Room,
+    Direct,
+}
+
+// Counters and gauges for the Prometheus scrape endpoint; all updates are lock-free
+#[derive(Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    connections_active: AtomicI64,
+    sessions_cleaned_up_total: AtomicU64,
+    session_seconds_total: AtomicU64,
+    messages_total: AtomicU64,
+    broadcasts_total: AtomicU64,
+    broadcast_deliveries_total: AtomicU64,
+    broadcast_failures_total: AtomicU64,
+    room_deliveries_total: AtomicU64,
+    room_failures_total: AtomicU64,
+    direct_deliveries_total: AtomicU64,
+    direct_failures_total: AtomicU64,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn session_cleaned_up(&self, connected_secs: u64) {
+        self.sessions_cleaned_up_total.fetch_add(1, Ordering::Relaxed);
+        self.session_seconds_total.fetch_add(connected_secs, Ordering::Relaxed);
+    }
+
+    // One call per chat message accepted for relaying, whatever its audience
+    pub fn message_relayed(&self) {
+        self.messages_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // One call per broadcast, with how many recipients it was queued for and how many it could not reach
+    pub fn broadcast(&self, delivered: u64, failed: u64) {
+        self.broadcasts_total.fetch_add(1, Ordering::Relaxed);
+        self.broadcast_deliveries_total.fetch_add(delivered, Ordering::Relaxed);
+        self.broadcast_failures_total.fetch_add(failed, Ordering::Relaxed);
+    }
+
+    pub fn delivery(&self, kind: DeliveryKind, delivered: bool) {
+        let counter = match (kind, delivered) {
+            (DeliveryKind::Room, true) => &self.room_deliveries_total,
+            (DeliveryKind::Room, false) => &self.room_failures_total,
+            (DeliveryKind::Direct, true) => &self.direct_deliveries_total,
+            (DeliveryKind::Direct, false) => &self.direct_failures_total,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text exposition format; `users_online` is read from the registry at scrape time
+    pub fn render(&self, users_online: usize, uptime_secs: u64) -> String {
+        let mut out = String::new();
+        let get = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        metric(&mut out, "chat_uptime_seconds", "gauge", "Seconds since the server started", uptime_secs);
+        metric(&mut out, "chat_connections_total", "counter", "Connections accepted", get(&self.connections_total));
+        metric(
+            &mut out,
+            "chat_connections_active",
+            "gauge",
+            "Connections currently open",
+            self.connections_active.load(Ordering::Relaxed),
+        );
+        metric(&mut out, "chat_users_online", "gauge", "Users currently joined", users_online);
+        metric(
+            &mut out,
+            "chat_sessions_cleaned_up_total",
+            "counter",
+            "Sessions removed after their connection ended",
+            get(&self.sessions_cleaned_up_total),
+        );
+        metric(
+            &mut out,
+            "chat_session_seconds_total",
+            "counter",
+            "Total seconds cleaned up sessions were connected for",
+            get(&self.session_seconds_total),
+        );
+        metric(
+            &mut out,
+            "chat_messages_total",
+            "counter",
+            "Chat messages accepted for relaying, to any audience",
+            get(&self.messages_total),
+        );
+        metric(
+            &mut out,
+            "chat_broadcasts_total",
+            "counter",
+            "Chat messages sent to @all",
+            get(&self.broadcasts_total),
+        );
+
+        let _ = writeln!(out, "# HELP chat_deliveries_total Chat messages queued for a recipient");
+        let _ = writeln!(out, "# TYPE chat_deliveries_total counter");
+        let _ = writeln!(out, "chat_deliveries_total{{kind=\"broadcast\"}} {}", get(&self.broadcast_deliveries_total));
+        let _ = writeln!(out, "chat_deliveries_total{{kind=\"room\"}} {}", get(&self.room_deliveries_total));
+        let _ = writeln!(out, "chat_deliveries_total{{kind=\"direct\"}} {}", get(&self.direct_deliveries_total));
+        let _ = writeln!(out, "# HELP chat_delivery_failures_total Chat messages that could not be queued for a recipient");
+        let _ = writeln!(out, "# TYPE chat_delivery_failures_total counter");
+        let _ = writeln!(out, "chat_delivery_failures_total{{kind=\"broadcast\"}} {}", get(&self.broadcast_failures_total));
+        let _ = writeln!(out, "chat_delivery_failures_total{{kind=\"room\"}} {}", get(&self.room_failures_total));
+        let _ = writeln!(out, "chat_delivery_failures_total{{kind=\"direct\"}} {}", get(&self.direct_failures_total));
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Answers GET /metrics until the task is aborted at shutdown
+pub async fn serve(listener: TcpListener, server: Arc<Server>) {
+    let app = Router::new().route("/metrics", get(scrape)).with_state(server);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!(error = %e, "Metrics listener failed");
+    }
+}
+
+async fn scrape(State(server): State<Arc<Server>>) -> impl IntoResponse {
+    let body = server.metrics.render(server.registry.joined_count(), server.started.elapsed().as_secs());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counts_messages_and_labels_deliveries() {
+        let metrics = Metrics::default();
+        metrics.message_relayed();
+        metrics.message_relayed();
+        metrics.delivery(DeliveryKind::Room, true);
+        metrics.delivery(DeliveryKind::Room, false);
+        metrics.delivery(DeliveryKind::Direct, true);
+        metrics.broadcast(3, 1);
+
+        let out = metrics.render(4, 60);
+        for line in [
+            "chat_messages_total 2",
+            "chat_users_online 4",
+            "chat_uptime_seconds 60",
+            "chat_broadcasts_total 1",
+            "chat_deliveries_total{kind=\"broadcast\"} 3",
+            "chat_deliveries_total{kind=\"room\"} 1",
+            "chat_deliveries_total{kind=\"direct\"} 1",
+            "chat_delivery_failures_total{kind=\"broadcast\"} 1",
+            "chat_delivery_failures_total{kind=\"room\"} 1",
+            "chat_delivery_failures_total{kind=\"direct\"} 0",
+        ] {
+            assert!(out.lines().any(|rendered| rendered == line), "missing {:?} in\n{}", line, out);
+        }
+    }
+}
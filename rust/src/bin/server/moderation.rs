@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// What a BAN applies to: anything that parses as an IP address is one, everything else is a username
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    Username(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    pub fn parse(target: &str) -> Self {
+        match target.parse() {
+            Ok(ip) => BanTarget::Ip(ip),
+            Err(_) => BanTarget::Username(target.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Username(username) => f.write_str(username),
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+// Bans and mutes with their expiry times; held in memory only, so a restart clears them
+#[derive(Default)]
+pub struct Moderation {
+    bans: Mutex<HashMap<BanTarget, Instant>>,
+    mutes: Mutex<HashMap<String, Instant>>,
+}
+
+impl Moderation {
+    // Returns false, adding nothing, if the duration runs past what the clock can represent
+    pub fn ban(&self, target: BanTarget, duration: Duration) -> bool {
+        // Worked out before taking the lock so a bad duration can never poison it
+        let Some(until) = Instant::now().checked_add(duration) else {
+            return false;
+        };
+        self.bans.lock().unwrap().insert(target, until);
+        true
+    }
+
+    // Returns false if there was no ban to lift
+    pub fn unban(&self, target: &BanTarget) -> bool {
+        self.bans.lock().unwrap().remove(target).is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn is_banned(&self, target: &BanTarget) -> bool {
+        active(&mut self.bans.lock().unwrap(), target)
+    }
+
+    // Returns false, adding nothing, if the duration runs past what the clock can represent
+    pub fn mute(&self, username: &str, duration: Duration) -> bool {
+        let Some(until) = Instant::now().checked_add(duration) else {
+            return false;
+        };
+        self.mutes.lock().unwrap().insert(username.to_string(), until);
+        true
+    }
+
+    // Returns false if the user was not muted
+    pub fn unmute(&self, username: &str) -> bool {
+        self.mutes.lock().unwrap().remove(username).is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn is_muted(&self, username: &str) -> bool {
+        active(&mut self.mutes.lock().unwrap(), username)
+    }
+}
+
+// Checks an entry, forgetting it if it has expired
+fn active<K, Q>(entries: &mut HashMap<K, Instant>, key: &Q) -> bool
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+{
+    match entries.get(key) {
+        Some(until) if *until > Instant::now() => true,
+        Some(_) => {
+            entries.remove(key);
+            false
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflowing_durations_are_refused_without_poisoning() {
+        let moderation = Moderation::default();
+        let forever = Duration::from_secs(u64::MAX);
+        assert!(!moderation.ban(BanTarget::parse("bob"), forever));
+        assert!(!moderation.mute("bob", forever));
+
+        // Both maps are still usable and hold nothing
+        assert!(!moderation.is_banned(&BanTarget::parse("bob")));
+        assert!(!moderation.is_muted("bob"));
+        assert!(moderation.ban(BanTarget::parse("bob"), Duration::from_secs(60)));
+        assert!(moderation.is_banned(&BanTarget::Username("bob".to_string())));
+    }
+
+    #[test]
+    fn expired_entries_are_forgotten() {
+        let moderation = Moderation::default();
+        assert!(moderation.mute("bob", Duration::ZERO));
+        assert!(!moderation.is_muted("bob"));
+        assert!(!moderation.unmute("bob"));
+
+        assert!(moderation.ban(BanTarget::parse("192.0.2.7"), Duration::from_secs(60)));
+        assert!(moderation.is_banned(&BanTarget::Ip("192.0.2.7".parse().unwrap())));
+        assert!(moderation.unban(&BanTarget::parse("192.0.2.7")));
+        assert!(!moderation.is_banned(&BanTarget::parse("192.0.2.7")));
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::sync::{watch, Notify};
+use tracing::warn;
+
+const DEFAULT_CAPACITY: usize = 256;
+
+// What to do when a client's outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    Disconnect,
+}
+
+impl fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverflowPolicy::DropOldest => f.write_str("drop-oldest"),
+            OverflowPolicy::Disconnect => f.write_str("disconnect"),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}'", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OutboundConfig {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl OutboundConfig {
+    // Reads CHAT_OUTBOUND_CAPACITY and CHAT_OVERFLOW_POLICY, falling back to the defaults
+    pub fn from_env() -> Self {
+        let capacity = std::env::var("CHAT_OUTBOUND_CAPACITY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&capacity| capacity > 0)
+            .unwrap_or(DEFAULT_CAPACITY);
+        let policy = std::env::var("CHAT_OVERFLOW_POLICY")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(OverflowPolicy::DropOldest);
+
+        OutboundConfig { capacity, policy }
+    }
+}
+
+// A bounded queue of encoded lines waiting to be written to one client
+pub struct Outbound {
+    peer_addr: String,
+    config: OutboundConfig,
+    queue: Mutex<VecDeque<String>>,
+    notify: Notify,
+    closed: watch::Sender<bool>,
+}
+
+impl Outbound {
+    pub fn new(peer_addr: String, config: OutboundConfig) -> Self {
+        Outbound {
+            peer_addr,
+            config,
+            queue: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: watch::channel(false).0,
+        }
+    }
+
+    // Queues a line without waiting on the socket; returns false if the line was not queued
+    pub fn push(&self, line: String) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if self.is_closed() {
+            return false;
+        }
+
+        if queue.len() >= self.config.capacity {
+            match self.config.policy {
+                OverflowPolicy::DropOldest => {
+                    queue.pop_front();
+                    warn!(peer = %self.peer_addr, "Outbound queue full, dropped the oldest message");
+                }
+                OverflowPolicy::Disconnect => {
+                    queue.clear();
+                    drop(queue);
+                    warn!(peer = %self.peer_addr, "Outbound queue full, disconnecting slow client");
+                    self.close();
+                    return false;
+                }
+            }
+        }
+
+        queue.push_back(line);
+        self.notify.notify_one();
+        true
+    }
+
+    // Waits for the next queued line; returns None once closed and drained
+    pub async fn pop(&self) -> Option<String> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(line) = queue.pop_front() {
+                    return Some(line);
+                }
+                if self.is_closed() {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    // Stops accepting new lines; anything already queued is still handed to the writer
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    // Resolves once the queue has been closed, by either side
+    pub async fn closed(&self) {
+        let mut closed = self.closed.subscribe();
+        let _ = closed.wait_for(|&closed| closed).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbound(capacity: usize, policy: OverflowPolicy) -> Outbound {
+        Outbound::new("127.0.0.1:5000".to_string(), OutboundConfig { capacity, policy })
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_the_oldest_line() {
+        let outbound = outbound(2, OverflowPolicy::DropOldest);
+        for line in ["one", "two", "three"] {
+            assert!(outbound.push(line.to_string()));
+        }
+        outbound.close();
+
+        assert_eq!(outbound.pop().await.as_deref(), Some("two"));
+        assert_eq!(outbound.pop().await.as_deref(), Some("three"));
+        assert_eq!(outbound.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn full_queue_disconnects_under_the_disconnect_policy() {
+        let outbound = outbound(2, OverflowPolicy::Disconnect);
+        assert!(outbound.push("one".to_string()));
+        assert!(outbound.push("two".to_string()));
+        assert!(!outbound.push("three".to_string()));
+
+        // Nothing more is queued or written once the slow client is cut off
+        assert!(outbound.is_closed());
+        assert!(!outbound.push("four".to_string()));
+        assert_eq!(outbound.pop().await, None);
+    }
+
+    #[test]
+    fn policies_parse_from_their_names() {
+        for policy in [OverflowPolicy::DropOldest, OverflowPolicy::Disconnect] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("drop-newest".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::session::Identity;
+use homework4::protocol::ReceiptState;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+// Messages whose receipts are remembered; older ones can no longer be acknowledged
+const TRACKED_MESSAGES: usize = 10_000;
+
+struct Tracked {
+    // None for messages from outside any session, such as the REST API
+    sender: Option<Identity>,
+    // Each recipient and the furthest state they have acknowledged
+    recipients: HashMap<Identity, Option<ReceiptState>>,
+}
+
+#[derive(Default)]
+struct ReceiptsState {
+    messages: HashMap<u64, Tracked>,
+    // Message IDs oldest first, for forgetting the oldest once the limit is reached
+    order: VecDeque<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckError {
+    // Unknown or forgotten ID, or the caller was not one of its recipients
+    NoSuchMessage,
+}
+
+// Who each recent message went to and how far each recipient has got with it
+#[derive(Default)]
+pub struct Receipts {
+    state: Mutex<ReceiptsState>,
+}
+
+impl Receipts {
+    pub fn track(&self, id: u64, sender: Option<Identity>, recipients: impl IntoIterator<Item = Identity>) {
+        let tracked = Tracked {
+            sender,
+            recipients: recipients.into_iter().map(|recipient| (recipient, None)).collect(),
+        };
+        if tracked.recipients.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.messages.insert(id, tracked);
+        state.order.push_back(id);
+        while state.order.len() > TRACKED_MESSAGES {
+            if let Some(oldest) = state.order.pop_front() {
+                state.messages.remove(&oldest);
+            }
+        }
+    }
+
+    // Records an ACK; returns the sender to notify, or None if there is nobody to tell or this is not news
+    pub fn acknowledge(&self, id: u64, recipient: &Identity, ack: ReceiptState) -> Result<Option<Identity>, AckError> {
+        let mut state = self.state.lock().unwrap();
+        let tracked = state.messages.get_mut(&id).ok_or(AckError::NoSuchMessage)?;
+        let reached = tracked.recipients.get_mut(recipient).ok_or(AckError::NoSuchMessage)?;
+
+        // READ implies DELIVERED, so a late DELIVERED after READ changes nothing
+        if reached.is_some_and(|reached| reached >= ack) {
+            return Ok(None);
+        }
+        *reached = Some(ack);
+        Ok(tracked.sender.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(username: &str) -> Identity {
+        Identity::Account(username.to_string())
+    }
+
+    #[test]
+    fn acks_only_move_forward_and_only_from_recipients() {
+        let receipts = Receipts::default();
+        receipts.track(1, Some(account("amy")), [account("bob"), Identity::Guest(7)]);
+
+        assert_eq!(receipts.acknowledge(1, &account("dan"), ReceiptState::Read), Err(AckError::NoSuchMessage));
+        assert_eq!(receipts.acknowledge(1, &account("bob"), ReceiptState::Read), Ok(Some(account("amy"))));
+        assert_eq!(receipts.acknowledge(1, &account("bob"), ReceiptState::Delivered), Ok(None));
+    }
+
+    #[test]
+    fn a_later_guest_with_the_same_name_cannot_acknowledge() {
+        let receipts = Receipts::default();
+        receipts.track(1, Some(account("amy")), [Identity::Guest(7)]);
+
+        assert_eq!(receipts.acknowledge(1, &Identity::Guest(9), ReceiptState::Read), Err(AckError::NoSuchMessage));
+        assert_eq!(receipts.acknowledge(1, &Identity::Guest(7), ReceiptState::Read), Ok(Some(account("amy"))));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::outbound::Outbound;
+use chrono::{DateTime, Utc};
+use homework4::protocol::Status;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+pub type SessionId = u64;
+
+// Where a connection is in its lifecycle; only joined sessions take part in chat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Joined,
+    Leaving,
+}
+
+pub struct Session {
+    pub id: SessionId,
+    pub ip: IpAddr,
+    pub state: ConnectionState,
+    pub username: Option<String>,
+    // Joined through LOGIN, so the username stays theirs after they leave
+    pub account: bool,
+    pub status: Status,
+    // Granted by ADMIN, or at login for a configured admin account
+    pub admin: bool,
+    // When this session's last TYPING was relayed
+    pub last_typing: Option<Instant>,
+    pub connected_at: DateTime<Utc>,
+    pub outbound: Arc<Outbound>,
+}
+
+// Who took part in a message, in a form that a later holder of the same name cannot match.
+// An account is the same person whenever it logs in; a guest is only the session that received the message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Identity {
+    Account(String),
+    Guest(SessionId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomError {
+    NoSuchRoom,
+    NotInRoom,
+}
+
+#[derive(Default)]
+struct Sessions {
+    by_id: HashMap<SessionId, Session>,
+    by_username: HashMap<String, SessionId>,
+    // Room name to member sessions; a room exists only while it has members
+    rooms: HashMap<String, BTreeSet<SessionId>>,
+    shutting_down: bool,
+}
+
+// Every live connection, indexed by session ID and by joined username under one lock
+#[derive(Default)]
+pub struct Registry {
+    sessions: RwLock<Sessions>,
+    next_id: AtomicU64,
+}
+
+impl Registry {
+    // Adds a new connection; returns None once the server is shutting down
+    pub fn register(&self, ip: IpAddr, outbound: Arc<Outbound>) -> Option<SessionId> {
+        let mut sessions = self.sessions.write().unwrap();
+        if sessions.shutting_down {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Session {
+            id,
+            ip,
+            state: ConnectionState::Connected,
+            username: None,
+            account: false,
+            status: Status::Online,
+            admin: false,
+            last_typing: None,
+            connected_at: Utc::now(),
+            outbound,
+        };
+        sessions.by_id.insert(id, session);
+        Some(id)
+    }
+
+    pub fn state(&self, id: SessionId) -> Option<ConnectionState> {
+        self.sessions.read().unwrap().by_id.get(&id).map(|session| session.state)
+    }
+
+    // Binds a username to a connected session; fails if the name is taken or the session is not waiting to join.
+    // `account` is true for a LOGIN, false for a guest JOIN.
+    pub fn join(&self, id: SessionId, username: &str, account: bool) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        if sessions.by_username.contains_key(username) {
+            return false;
+        }
+        let Some(session) = sessions.by_id.get_mut(&id) else {
+            return false;
+        };
+        if session.state != ConnectionState::Connected {
+            return false;
+        }
+        session.state = ConnectionState::Joined;
+        session.username = Some(username.to_string());
+        session.account = account;
+        sessions.by_username.insert(username.to_string(), id);
+        true
+    }
+
+    // Marks the session as leaving and frees its username for someone else; returns the name it had
+    pub fn leave(&self, id: SessionId) -> Option<String> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions.by_id.get_mut(&id)?;
+        session.state = ConnectionState::Leaving;
+        let username = session.username.clone()?;
+        unbind_username(&mut sessions, &username, id);
+        leave_all_rooms(&mut sessions, id);
+        Some(username)
+    }
+
+    pub fn remove(&self, id: SessionId) -> Option<Session> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions.by_id.remove(&id)?;
+        if let Some(username) = &session.username {
+            unbind_username(&mut sessions, username, id);
+        }
+        leave_all_rooms(&mut sessions, id);
+        Some(session)
+    }
+
+    // Adds a joined session to a room, creating the room if needed
+    pub fn join_room(&self, id: SessionId, room: &str) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        match sessions.by_id.get(&id) {
+            Some(session) if session.state == ConnectionState::Joined => {}
+            _ => return false,
+        }
+        sessions.rooms.entry(room.to_string()).or_default().insert(id);
+        true
+    }
+
+    pub fn part_room(&self, id: SessionId, room: &str) -> Result<(), RoomError> {
+        let mut sessions = self.sessions.write().unwrap();
+        let members = sessions.rooms.get_mut(room).ok_or(RoomError::NoSuchRoom)?;
+        if !members.remove(&id) {
+            return Err(RoomError::NotInRoom);
+        }
+        if members.is_empty() {
+            sessions.rooms.remove(room);
+        }
+        Ok(())
+    }
+
+    // Every room with the usernames of its members
+    pub fn rooms(&self) -> BTreeMap<String, Vec<String>> {
+        let sessions = self.sessions.read().unwrap();
+        sessions
+            .rooms
+            .iter()
+            .map(|(room, members)| {
+                let usernames = members
+                    .iter()
+                    .filter_map(|id| sessions.by_id.get(id).and_then(|session| session.username.clone()))
+                    .collect();
+                (room.clone(), usernames)
+            })
+            .collect()
+    }
+
+    pub fn check_room_member(&self, id: SessionId, room: &str) -> Result<(), RoomError> {
+        let sessions = self.sessions.read().unwrap();
+        let members = sessions.rooms.get(room).ok_or(RoomError::NoSuchRoom)?;
+        if members.contains(&id) {
+            Ok(())
+        } else {
+            Err(RoomError::NotInRoom)
+        }
+    }
+
+    // Outbound handles for a room's members, provided `id` is one of them
+    pub fn room_outbounds(&self, id: SessionId, room: &str) -> Result<Vec<(SessionId, Arc<Outbound>)>, RoomError> {
+        let sessions = self.sessions.read().unwrap();
+        let members = sessions.rooms.get(room).ok_or(RoomError::NoSuchRoom)?;
+        if !members.contains(&id) {
+            return Err(RoomError::NotInRoom);
+        }
+        Ok(members
+            .iter()
+            .filter_map(|member| sessions.by_id.get(member))
+            .filter(|session| session.state == ConnectionState::Joined)
+            .map(|session| (session.id, Arc::clone(&session.outbound)))
+            .collect())
+    }
+
+    pub fn username(&self, id: SessionId) -> Option<String> {
+        self.sessions.read().unwrap().by_id.get(&id).and_then(|session| session.username.clone())
+    }
+
+    pub fn outbound_for(&self, username: &str) -> Option<(SessionId, Arc<Outbound>)> {
+        let sessions = self.sessions.read().unwrap();
+        let id = sessions.by_username.get(username)?;
+        sessions.by_id.get(id).map(|session| (session.id, Arc::clone(&session.outbound)))
+    }
+
+    pub fn identity(&self, id: SessionId) -> Option<Identity> {
+        let sessions = self.sessions.read().unwrap();
+        let session = sessions.by_id.get(&id).filter(|session| session.state == ConnectionState::Joined)?;
+        Some(identity_of(session))
+    }
+
+    // The session an identity is joined as right now; a guest identity never outlives its session
+    pub fn outbound_for_identity(&self, identity: &Identity) -> Option<(SessionId, Arc<Outbound>)> {
+        let sessions = self.sessions.read().unwrap();
+        let session = match identity {
+            Identity::Account(username) => sessions
+                .by_username
+                .get(username)
+                .and_then(|id| sessions.by_id.get(id))
+                .filter(|session| session.account)?,
+            Identity::Guest(id) => sessions.by_id.get(id)?,
+        };
+        (session.state == ConnectionState::Joined).then(|| (session.id, Arc::clone(&session.outbound)))
+    }
+
+    pub fn set_admin(&self, id: SessionId) {
+        if let Some(session) = self.sessions.write().unwrap().by_id.get_mut(&id) {
+            session.admin = true;
+        }
+    }
+
+    pub fn is_admin(&self, id: SessionId) -> bool {
+        self.sessions.read().unwrap().by_id.get(&id).is_some_and(|session| session.admin)
+    }
+
+    // Rate limit for TYPING: notes the time and returns true unless the last one was under `interval` ago
+    pub fn typing_allowed(&self, id: SessionId, interval: Duration) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let Some(session) = sessions.by_id.get_mut(&id) else {
+            return false;
+        };
+        let now = Instant::now();
+        if session.last_typing.is_some_and(|last| now.duration_since(last) < interval) {
+            return false;
+        }
+        session.last_typing = Some(now);
+        true
+    }
+
+    // Every connection from an address, joined or not, for enforcing an IP ban
+    pub fn outbounds_from(&self, ip: IpAddr) -> Vec<(SessionId, Arc<Outbound>)> {
+        self.sessions
+            .read()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|session| session.ip == ip)
+            .map(|session| (session.id, Arc::clone(&session.outbound)))
+            .collect()
+    }
+
+    pub fn set_status(&self, username: &str, status: Status) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let Some(&id) = sessions.by_username.get(username) else {
+            return false;
+        };
+        match sessions.by_id.get_mut(&id) {
+            Some(session) => {
+                session.status = status;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Joined usernames and their statuses
+    pub fn board(&self) -> BTreeMap<String, Status> {
+        self.sessions
+            .read()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|session| session.state == ConnectionState::Joined)
+            .filter_map(|session| session.username.clone().map(|username| (username, session.status)))
+            .collect()
+    }
+
+    // Moves every session to Leaving so nothing more is joined or delivered; returns each outbound and whether it had joined
+    pub fn shut_down(&self) -> Vec<(Arc<Outbound>, bool)> {
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.shutting_down = true;
+        sessions
+            .by_id
+            .values_mut()
+            .map(|session| {
+                let joined = session.state == ConnectionState::Joined;
+                session.state = ConnectionState::Leaving;
+                (Arc::clone(&session.outbound), joined)
+            })
+            .collect()
+    }
+
+    pub fn joined_count(&self) -> usize {
+        self.sessions.read().unwrap().by_username.len()
+    }
+
+    // Identities of every joined session, for tracking who a broadcast reached
+    pub fn joined_identities(&self) -> Vec<Identity> {
+        self.sessions
+            .read()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|session| session.state == ConnectionState::Joined)
+            .map(identity_of)
+            .collect()
+    }
+
+    // Outbound handles for every joined session, for fanning out a broadcast
+    pub fn joined_outbounds(&self) -> Vec<(SessionId, Arc<Outbound>)> {
+        self.sessions
+            .read()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|session| session.state == ConnectionState::Joined)
+            .map(|session| (session.id, Arc::clone(&session.outbound)))
+            .collect()
+    }
+}
+
+fn identity_of(session: &Session) -> Identity {
+    match (&session.username, session.account) {
+        (Some(username), true) => Identity::Account(username.clone()),
+        _ => Identity::Guest(session.id),
+    }
+}
+
+// Drops the index entry only if it still points at this session, since a left name may already be reused
+fn unbind_username(sessions: &mut Sessions, username: &str, id: SessionId) {
+    if sessions.by_username.get(username) == Some(&id) {
+        sessions.by_username.remove(username);
+    }
+}
+
+fn leave_all_rooms(sessions: &mut Sessions, id: SessionId) {
+    sessions.rooms.retain(|_, members| {
+        members.remove(&id);
+        !members.is_empty()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outbound::{OutboundConfig, OverflowPolicy};
+    use std::net::Ipv4Addr;
+
+    fn connect(registry: &Registry) -> SessionId {
+        let config = OutboundConfig {
+            capacity: 8,
+            policy: OverflowPolicy::DropOldest,
+        };
+        let outbound = Arc::new(Outbound::new("127.0.0.1:5000".to_string(), config));
+        registry.register(IpAddr::V4(Ipv4Addr::LOCALHOST), outbound).unwrap()
+    }
+
+    #[test]
+    fn typing_is_relayed_at_most_once_per_interval() {
+        let registry = Registry::default();
+        let alice = connect(&registry);
+        let bob = connect(&registry);
+        let interval = Duration::from_millis(50);
+
+        assert!(registry.typing_allowed(alice, interval));
+        assert!(!registry.typing_allowed(alice, interval));
+        // Each session has its own allowance
+        assert!(registry.typing_allowed(bob, interval));
+
+        std::thread::sleep(interval);
+        assert!(registry.typing_allowed(alice, interval));
+        assert!(!registry.typing_allowed(SessionId::MAX, interval));
+    }
+
+    #[test]
+    fn a_reused_guest_name_is_a_different_identity() {
+        let registry = Registry::default();
+        let first = connect(&registry);
+        assert!(registry.join(first, "bob", false));
+        let guest = registry.identity(first).unwrap();
+        assert_eq!(guest, Identity::Guest(first));
+        registry.leave(first);
+
+        let second = connect(&registry);
+        assert!(registry.join(second, "bob", false));
+        assert_ne!(registry.identity(second), Some(guest.clone()));
+        assert!(registry.outbound_for_identity(&guest).is_none());
+    }
+
+    #[test]
+    fn an_account_is_found_in_whichever_session_logged_in() {
+        let registry = Registry::default();
+        let account = Identity::Account("alice".to_string());
+        let first = connect(&registry);
+        assert!(registry.join(first, "alice", true));
+        assert_eq!(registry.identity(first), Some(account.clone()));
+        registry.remove(first);
+
+        let second = connect(&registry);
+        assert!(registry.join(second, "alice", true));
+        assert_eq!(registry.outbound_for_identity(&account).map(|(id, _)| id), Some(second));
+    }
+}
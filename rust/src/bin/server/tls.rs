@@ -0,0 +1,46 @@
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    // PEM certificate chain, leaf first
+    pub cert_path: PathBuf,
+    // PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    // Reads CHAT_TLS_CERT and CHAT_TLS_KEY; TLS is off unless both are set
+    pub fn from_env() -> Option<Self> {
+        let cert_path = std::env::var_os("CHAT_TLS_CERT").map(PathBuf::from)?;
+        let key_path = std::env::var_os("CHAT_TLS_KEY").map(PathBuf::from)?;
+        Some(TlsConfig { cert_path, key_path })
+    }
+
+    pub fn acceptor(&self) -> std::io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(format!("could not read certificates from {}: {}", self.cert_path.display(), e)))?;
+        if certs.is_empty() {
+            return Err(invalid(format!("no certificates found in {}", self.cert_path.display())));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| invalid(format!("could not read private key from {}: {}", self.key_path.display(), e)))?;
+
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid(e.to_string()))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid(format!("certificate and key do not match: {}", e)))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
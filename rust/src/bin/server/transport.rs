@@ -0,0 +1,223 @@
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+// Where a connection's command lines come from
+pub trait LineReader {
+    // Waits for more input and returns the lines it completed, possibly none; None once the peer has gone.
+    // Cancelling it loses nothing, so it can sit in a select! with the heartbeat timers.
+    fn read_lines(&mut self) -> impl Future<Output = io::Result<Option<Vec<Line>>>> + Send;
+}
+
+// Where a connection's responses and events go, one encoded line at a time
+pub trait LineWriter {
+    fn write_line(&mut self, line: &str) -> impl Future<Output = io::Result<()>> + Send;
+    fn shutdown(&mut self) -> impl Future<Output = ()> + Send;
+}
+
+// Plain TCP or TLS: a byte stream of `\n`-terminated lines
+pub fn split_stream<S>(stream: S, max_line_length: usize) -> (StreamReader<S>, StreamWriter<S>)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = StreamReader {
+        reader,
+        lines: LineBuffer::new(max_line_length),
+    };
+    (reader, StreamWriter(writer))
+}
+
+pub struct StreamReader<S> {
+    reader: ReadHalf<S>,
+    lines: LineBuffer,
+}
+
+impl<S: AsyncRead + Send> LineReader for StreamReader<S> {
+    async fn read_lines(&mut self) -> io::Result<Option<Vec<Line>>> {
+        let mut buffer = [0; 1024];
+        let size = self.reader.read(&mut buffer).await?;
+        if size == 0 {
+            return Ok(None);
+        }
+        self.lines.extend(&buffer[..size]);
+        Ok(Some(self.lines.drain()))
+    }
+}
+
+pub struct StreamWriter<S>(WriteHalf<S>);
+
+impl<S: AsyncWrite + Send> LineWriter for StreamWriter<S> {
+    async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.0.write_all(line.as_bytes()).await
+    }
+
+    async fn shutdown(&mut self) {
+        let _ = self.0.shutdown().await;
+    }
+}
+
+// WebSocket: every text frame is one line, with or without its trailing newline
+pub fn split_websocket<S>(stream: WebSocketStream<S>, max_line_length: usize) -> (WebSocketReader<S>, WebSocketWriter<S>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (sink, stream) = stream.split();
+    let reader = WebSocketReader {
+        stream,
+        lines: LineBuffer::new(max_line_length),
+    };
+    (reader, WebSocketWriter(sink))
+}
+
+pub struct WebSocketReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    lines: LineBuffer,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> LineReader for WebSocketReader<S> {
+    async fn read_lines(&mut self) -> io::Result<Option<Vec<Line>>> {
+        match self.stream.next().await {
+            None | Some(Ok(Message::Close(_))) => Ok(None),
+            Some(Ok(Message::Text(text))) => {
+                // Going through the line buffer means a frame holding several lines is split, never relayed whole
+                self.lines.extend(text.as_bytes());
+                self.lines.extend(b"\n");
+                Ok(Some(self.lines.drain()))
+            }
+            // Pings, pongs and binary frames show the connection is alive but carry no command
+            Some(Ok(_)) => Ok(Some(Vec::new())),
+            Some(Err(e)) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+pub struct WebSocketWriter<S>(SplitSink<WebSocketStream<S>, Message>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> LineWriter for WebSocketWriter<S> {
+    async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let text = line.trim_end_matches('\n').to_string();
+        self.0.send(Message::text(text)).await.map_err(io::Error::other)
+    }
+
+    async fn shutdown(&mut self) {
+        let _ = self.0.close().await;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    Complete(String),
+    TooLong,
+}
+
+// Buffers raw socket input and hands back one `\n`-terminated command at a time
+struct LineBuffer {
+    buffer: Vec<u8>,
+    max_length: usize,
+    discarding: bool,
+}
+
+impl LineBuffer {
+    fn new(max_length: usize) -> Self {
+        LineBuffer {
+            buffer: Vec::new(),
+            max_length,
+            discarding: false,
+        }
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Every complete line buffered so far
+    fn drain(&mut self) -> Vec<Line> {
+        std::iter::from_fn(|| self.next_line()).collect()
+    }
+
+    fn next_line(&mut self) -> Option<Line> {
+        loop {
+            match self.buffer.iter().position(|&b| b == b'\n') {
+                Some(position) => {
+                    let line: Vec<u8> = self.buffer.drain(..=position).collect();
+                    if self.discarding {
+                        // The start of this line was already reported as too long
+                        self.discarding = false;
+                        continue;
+                    }
+                    if position > self.max_length {
+                        return Some(Line::TooLong);
+                    }
+                    let line = String::from_utf8_lossy(&line[..position]).trim().to_string();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    return Some(Line::Complete(line));
+                }
+                None => {
+                    if self.buffer.len() > self.max_length {
+                        self.buffer.clear();
+                        if !self.discarding {
+                            self.discarding = true;
+                            return Some(Line::TooLong);
+                        }
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(line: &str) -> Line {
+        Line::Complete(line.to_string())
+    }
+
+    #[test]
+    fn lines_split_across_reads_are_joined() {
+        let mut lines = LineBuffer::new(64);
+        lines.extend(b"JOIN al");
+        assert_eq!(lines.drain(), vec![]);
+        lines.extend(b"ice\r\nUSERBOARD\n\nROO");
+        assert_eq!(lines.drain(), vec![complete("JOIN alice"), complete("USERBOARD")]);
+        lines.extend(b"MS\n");
+        assert_eq!(lines.drain(), vec![complete("ROOMS")]);
+    }
+
+    #[test]
+    fn multibyte_characters_survive_a_split_read() {
+        let mut lines = LineBuffer::new(64);
+        let text = "SEND caf\u{e9}\n".as_bytes();
+        lines.extend(&text[..text.len() - 2]);
+        assert_eq!(lines.drain(), vec![]);
+        lines.extend(&text[text.len() - 2..]);
+        assert_eq!(lines.drain(), vec![complete("SEND caf\u{e9}")]);
+    }
+
+    #[test]
+    fn long_complete_line_is_reported_once() {
+        let mut lines = LineBuffer::new(8);
+        lines.extend(b"123456789\nPING\n");
+        assert_eq!(lines.drain(), vec![Line::TooLong, complete("PING")]);
+    }
+
+    #[test]
+    fn long_partial_line_is_discarded_up_to_its_newline() {
+        let mut lines = LineBuffer::new(8);
+        lines.extend(b"0123456789");
+        assert_eq!(lines.drain(), vec![Line::TooLong]);
+        lines.extend(b"more of the same line");
+        assert_eq!(lines.drain(), vec![]);
+        lines.extend(b" end\nPING\n");
+        assert_eq!(lines.drain(), vec![complete("PING")]);
+    }
+}
@@ -0,0 +1,2 @@
+// Code shared by the server and client binaries
+pub mod protocol;
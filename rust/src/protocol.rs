@@ -0,0 +1,1010 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Default longest chat message body, in characters after trimming; servers can set their own limit
+pub const MAX_MESSAGE_LENGTH: usize = 500;
+
+// Messages returned by HISTORY when no count is given
+pub const DEFAULT_HISTORY_COUNT: usize = 50;
+
+// Shortest password REGISTER accepts
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Keeps passwords out of `{:?}` output such as the server's command log
+#[derive(Clone, PartialEq, Eq)]
+pub struct Password(pub String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(***)")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Status {
+    Online,
+    Offline,
+    DoNotDisturb,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Online => "ONLINE",
+            Status::Offline => "OFFLINE",
+            Status::DoNotDisturb => "DO_NOT_DISTURB",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Status {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ONLINE" => Ok(Status::Online),
+            "OFFLINE" => Ok(Status::Offline),
+            "DO_NOT_DISTURB" => Ok(Status::DoNotDisturb),
+            _ => Err(ProtocolError::InvalidStatus(s.to_string())),
+        }
+    }
+}
+
+// The JSON body of a SEND, relayed to its recipients with the server's ID and time added
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default)]
+    pub header: String,
+    #[serde(default)]
+    pub sender: String,
+    pub message: String,
+    // Whatever the sending client put here, usually its local time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    // When the server relayed it, RFC 3339 in UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<String>,
+    // Set once the sender or an admin has changed the text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn is_broadcast(&self) -> bool {
+        self.header.trim() == "@all"
+    }
+
+    // Usernames named by `@user` words in the header
+    pub fn recipients(&self) -> Vec<&str> {
+        self.header
+            .split_whitespace()
+            .filter(|word| word.starts_with('@'))
+            .map(|user| user.trim_start_matches('@'))
+            .collect()
+    }
+
+    // Room names named by `#room` words in the header
+    pub fn rooms(&self) -> Vec<&str> {
+        self.header
+            .split_whitespace()
+            .filter(|word| word.starts_with('#'))
+            .map(|room| room.trim_start_matches('#'))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReceiptState {
+    Delivered,
+    Read,
+}
+
+impl fmt::Display for ReceiptState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiptState::Delivered => f.write_str("DELIVERED"),
+            ReceiptState::Read => f.write_str("READ"),
+        }
+    }
+}
+
+impl FromStr for ReceiptState {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "DELIVERED" => Ok(ReceiptState::Delivered),
+            "READ" => Ok(ReceiptState::Read),
+            _ => Err(ProtocolError::InvalidArguments(s.to_string())),
+        }
+    }
+}
+
+// Pushed to a message's sender when one of its recipients acknowledges it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub id: u64,
+    pub username: String,
+    pub state: ReceiptState,
+}
+
+// Pushed to a message's audience when its text is changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub id: u64,
+    pub message: String,
+    pub edited_at: String,
+}
+
+// A stored message and the ID used to page backwards from it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    #[serde(flatten)]
+    pub message: ChatMessage,
+}
+
+// One page of HISTORY for `@all`, a `#room` or an `@user` conversation, oldest first
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub target: String,
+    pub messages: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PresenceKind {
+    Join,
+    Leave,
+    Status,
+}
+
+// Pushed to every joined session when someone joins, leaves or changes status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceEvent {
+    pub event: PresenceKind,
+    pub username: String,
+    pub status: Status,
+}
+
+// Relayed to a TYPING header's audience; clients hide it after `expires_in` seconds unless it is repeated
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub username: String,
+    pub header: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // Anonymous guest join, if the server allows guests
+    Join(String),
+    Register { username: String, password: Password },
+    Login { username: String, password: Password },
+    Leave,
+    Send(ChatMessage),
+    UserBoard,
+    // Without a username this changes the caller's own status
+    UserStatus { username: Option<String>, status: Status },
+    JoinRoom(String),
+    PartRoom(String),
+    Rooms,
+    // HISTORY <target> [count] [before-id]
+    History {
+        target: String,
+        count: usize,
+        before: Option<u64>,
+    },
+    // Heartbeats; the optional token is echoed back unchanged
+    Ping(Option<String>),
+    Pong(Option<String>),
+    // ACK <id> <DELIVERED|READ>, from a recipient of that message
+    Ack { id: u64, state: ReceiptState },
+    // EDIT <id> <text> and DELETE <id>, from the message's sender or an admin
+    Edit { id: u64, message: String },
+    Delete(u64),
+    // TYPING <header>, the header of the message being written; never answered
+    Typing(String),
+    // Makes the caller an admin if the token matches the server's
+    Admin(Password),
+    // Admin only: KICK <username> [reason]
+    Kick { username: String, reason: Option<String> },
+    // Admin only: BAN <username|ip> <duration> [reason]
+    Ban { target: String, seconds: u64, reason: Option<String> },
+    Unban(String),
+    // Admin only: MUTE <username> <duration>
+    Mute { username: String, seconds: u64 },
+    Unmute(String),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, ProtocolError> {
+        let line = line.trim();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+
+        match command {
+            "JOIN" => Ok(Command::Join(argument.to_string())),
+            "REGISTER" => {
+                let (username, password) = credentials(argument)?;
+                Ok(Command::Register { username, password })
+            }
+            "LOGIN" => {
+                let (username, password) = credentials(argument)?;
+                Ok(Command::Login { username, password })
+            }
+            "LEAVE" => Ok(Command::Leave),
+            "SEND" => serde_json::from_str::<ChatMessage>(argument)
+                .map(Command::Send)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string())),
+            "USERBOARD" => Ok(Command::UserBoard),
+            "USERSTATUS" => match argument.split_whitespace().collect::<Vec<&str>>()[..] {
+                [status] => Ok(Command::UserStatus {
+                    username: None,
+                    status: status.parse()?,
+                }),
+                [username, status] => Ok(Command::UserStatus {
+                    username: Some(username.to_string()),
+                    status: status.parse()?,
+                }),
+                _ => Err(ProtocolError::InvalidArguments(argument.to_string())),
+            },
+            "JOINROOM" => room_argument(argument).map(Command::JoinRoom),
+            "PARTROOM" => room_argument(argument).map(Command::PartRoom),
+            "ROOMS" => Ok(Command::Rooms),
+            "HISTORY" => {
+                let invalid = || ProtocolError::InvalidArguments(argument.to_string());
+                let parts: Vec<&str> = argument.split_whitespace().collect();
+                let (target, count, before) = match parts[..] {
+                    [target] => (target, DEFAULT_HISTORY_COUNT, None),
+                    [target, count] => (target, count.parse().map_err(|_| invalid())?, None),
+                    [target, count, before] => (
+                        target,
+                        count.parse().map_err(|_| invalid())?,
+                        Some(before.parse().map_err(|_| invalid())?),
+                    ),
+                    _ => return Err(invalid()),
+                };
+                Ok(Command::History {
+                    target: target.to_string(),
+                    count,
+                    before,
+                })
+            }
+            "PING" => Ok(Command::Ping(token(argument))),
+            "PONG" => Ok(Command::Pong(token(argument))),
+            "ACK" => match argument.split_whitespace().collect::<Vec<&str>>()[..] {
+                [id, state] => Ok(Command::Ack {
+                    id: id.parse().map_err(|_| ProtocolError::InvalidArguments(argument.to_string()))?,
+                    state: state.parse()?,
+                }),
+                _ => Err(ProtocolError::InvalidArguments(argument.to_string())),
+            },
+            "EDIT" => {
+                let (id, message) = target_and_rest(argument)?;
+                match (id.parse(), message) {
+                    (Ok(id), Some(message)) => Ok(Command::Edit { id, message }),
+                    _ => Err(ProtocolError::InvalidArguments(argument.to_string())),
+                }
+            }
+            "DELETE" => single_word(argument)?
+                .parse()
+                .map(Command::Delete)
+                .map_err(|_| ProtocolError::InvalidArguments(argument.to_string())),
+            "TYPING" if !argument.is_empty() => Ok(Command::Typing(argument.to_string())),
+            "TYPING" => Err(ProtocolError::InvalidArguments("expected <header>".to_string())),
+            "ADMIN" => match argument.split_whitespace().collect::<Vec<&str>>()[..] {
+                [token] => Ok(Command::Admin(Password(token.to_string()))),
+                _ => Err(ProtocolError::InvalidArguments("expected <token>".to_string())),
+            },
+            "KICK" => {
+                let (username, reason) = target_and_rest(argument)?;
+                Ok(Command::Kick { username, reason })
+            }
+            "BAN" => {
+                let (target, rest) = target_and_rest(argument)?;
+                let (seconds, reason) = duration_and_rest(rest.as_deref().unwrap_or(""))?;
+                Ok(Command::Ban { target, seconds, reason })
+            }
+            "UNBAN" => single_word(argument).map(Command::Unban),
+            "MUTE" => {
+                let (username, rest) = target_and_rest(argument)?;
+                match duration_and_rest(rest.as_deref().unwrap_or(""))? {
+                    (seconds, None) => Ok(Command::Mute { username, seconds }),
+                    _ => Err(ProtocolError::InvalidArguments(argument.to_string())),
+                }
+            }
+            "UNMUTE" => single_word(argument).map(Command::Unmute),
+            _ => Err(ProtocolError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    // The wire form of the command, including the trailing newline
+    pub fn encode(&self) -> String {
+        match self {
+            Command::Join(username) => format!("JOIN {}\n", username),
+            Command::Register { username, password } => format!("REGISTER {} {}\n", username, password.0),
+            Command::Login { username, password } => format!("LOGIN {} {}\n", username, password.0),
+            Command::Leave => "LEAVE\n".to_string(),
+            Command::Send(message) => format!("SEND {}\n", encode_json(message)),
+            Command::UserBoard => "USERBOARD\n".to_string(),
+            Command::UserStatus { username: None, status } => format!("USERSTATUS {}\n", status),
+            Command::UserStatus {
+                username: Some(username),
+                status,
+            } => format!("USERSTATUS {} {}\n", username, status),
+            Command::JoinRoom(room) => format!("JOINROOM {}\n", room),
+            Command::PartRoom(room) => format!("PARTROOM {}\n", room),
+            Command::Rooms => "ROOMS\n".to_string(),
+            Command::History {
+                target,
+                count,
+                before: Some(before),
+            } => format!("HISTORY {} {} {}\n", target, count, before),
+            Command::History {
+                target,
+                count,
+                before: None,
+            } => format!("HISTORY {} {}\n", target, count),
+            Command::Ping(token) => format!("{}\n", with_token("PING", token)),
+            Command::Pong(token) => format!("{}\n", with_token("PONG", token)),
+            Command::Ack { id, state } => format!("ACK {} {}\n", id, state),
+            Command::Edit { id, message } => format!("EDIT {} {}\n", id, message),
+            Command::Delete(id) => format!("DELETE {}\n", id),
+            Command::Typing(header) => format!("TYPING {}\n", header),
+            Command::Admin(token) => format!("ADMIN {}\n", token.0),
+            Command::Kick { username, reason } => format!("{}\n", with_token(&format!("KICK {}", username), reason)),
+            Command::Ban { target, seconds, reason } => {
+                format!("{}\n", with_token(&format!("BAN {} {}s", target, seconds), reason))
+            }
+            Command::Unban(target) => format!("UNBAN {}\n", target),
+            Command::Mute { username, seconds } => format!("MUTE {} {}s\n", username, seconds),
+            Command::Unmute(username) => format!("UNMUTE {}\n", username),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Testing,
+    Ok,
+    Bye,
+    // Both carry the ID the server gave the message
+    Sent(u64),
+    Registered,
+    // Accepted, but at least one recipient is offline and will get it on their next JOIN
+    Queued(u64),
+    Acked,
+    MessageEdited,
+    MessageDeleted,
+    Board(BTreeMap<String, Status>),
+    StatusUpdated,
+    RoomJoined(String),
+    RoomLeft(String),
+    Rooms(BTreeMap<String, Vec<String>>),
+    History(HistoryPage),
+    InvalidUsername,
+    InvalidPassword,
+    AccountExists,
+    InvalidCredentials,
+    LoginRequired,
+    AlreadyLoggedIn,
+    AlreadyJoined,
+    NotJoined,
+    NotYourUser,
+    InvalidRoomName,
+    NotInRoom,
+    NoSuchRoom,
+    MessageFailed,
+    InvalidMessageFormat,
+    InvalidRequest,
+    LineTooLong,
+    // Sent just before the server drops a connection that has not sent a command for too long
+    IdleTimeout,
+    AdminGranted,
+    NotAdmin,
+    NoSuchUser,
+    NoSuchMessage,
+    // EDIT or DELETE of someone else's message by a non-admin
+    NotYourMessage,
+    UserKicked,
+    UserBanned,
+    UserUnbanned,
+    UserMuted,
+    UserUnmuted,
+    // The caller's username or address is banned
+    Banned,
+    // The caller is muted and cannot SEND until the mute expires
+    Muted,
+    ServerError,
+    // A chat message relayed from another user
+    Message(ChatMessage),
+    Presence(PresenceEvent),
+    Receipt(Receipt),
+    // A message the recipient already has was changed or removed
+    Edited(MessageEdit),
+    Deleted(u64),
+    Typing(TypingEvent),
+    // Sent to joined users before a planned shutdown, with the operator's reason if one was given
+    Shutdown(Option<String>),
+    // Sent to a user just before an admin disconnects them, with the admin's reason if one was given
+    Kicked(Option<String>),
+    Ping(Option<String>),
+    Pong(Option<String>),
+}
+
+impl Response {
+    pub fn parse(line: &str) -> Result<Response, ProtocolError> {
+        let line = line.trim();
+
+        if line.starts_with('{') {
+            return serde_json::from_str::<ChatMessage>(line)
+                .map(Response::Message)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(event) = line.strip_prefix("PRESENCE ") {
+            return serde_json::from_str::<PresenceEvent>(event.trim())
+                .map(Response::Presence)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(receipt) = line.strip_prefix("RECEIPT ") {
+            return serde_json::from_str::<Receipt>(receipt.trim())
+                .map(Response::Receipt)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(edit) = line.strip_prefix("EDITED ") {
+            return serde_json::from_str::<MessageEdit>(edit.trim())
+                .map(Response::Edited)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(event) = line.strip_prefix("TYPING ") {
+            return serde_json::from_str::<TypingEvent>(event.trim())
+                .map(Response::Typing)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(id) = line.strip_prefix("DELETED ") {
+            return message_id(id).map(Response::Deleted);
+        }
+
+        if let Some(id) = line.strip_prefix("200 SENT ") {
+            return message_id(id).map(Response::Sent);
+        }
+
+        if let Some(id) = line.strip_prefix("200 QUEUED ") {
+            return message_id(id).map(Response::Queued);
+        }
+
+        if let Some(rest) = line.strip_prefix("PING") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                return Ok(Response::Ping(token(rest)));
+            }
+        }
+
+        if let Some(rest) = line.strip_prefix("PONG") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                return Ok(Response::Pong(token(rest)));
+            }
+        }
+
+        if line == "SHUTDOWN" {
+            return Ok(Response::Shutdown(None));
+        }
+
+        if let Some(reason) = line.strip_prefix("SHUTDOWN ") {
+            return Ok(Response::Shutdown(Some(reason.trim().to_string())));
+        }
+
+        if line == "KICKED" {
+            return Ok(Response::Kicked(None));
+        }
+
+        if let Some(reason) = line.strip_prefix("KICKED ") {
+            return Ok(Response::Kicked(Some(reason.trim().to_string())));
+        }
+
+        if let Some(board) = line.strip_prefix("200 BOARD") {
+            return serde_json::from_str::<BTreeMap<String, Status>>(board.trim())
+                .map(Response::Board)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(rooms) = line.strip_prefix("200 ROOMS") {
+            return serde_json::from_str::<BTreeMap<String, Vec<String>>>(rooms.trim())
+                .map(Response::Rooms)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(page) = line.strip_prefix("200 HISTORY") {
+            return serde_json::from_str::<HistoryPage>(page.trim())
+                .map(Response::History)
+                .map_err(|e| ProtocolError::InvalidJson(e.to_string()));
+        }
+
+        if let Some(room) = line.strip_prefix("200 ROOM JOINED ") {
+            return Ok(Response::RoomJoined(room.trim().to_string()));
+        }
+
+        if let Some(room) = line.strip_prefix("200 ROOM LEFT ") {
+            return Ok(Response::RoomLeft(room.trim().to_string()));
+        }
+
+        match line {
+            "100 TESTING" => Ok(Response::Testing),
+            "200 OK" => Ok(Response::Ok),
+            "200 BYE" => Ok(Response::Bye),
+            "200 ACKED" => Ok(Response::Acked),
+            "200 EDITED" => Ok(Response::MessageEdited),
+            "200 DELETED" => Ok(Response::MessageDeleted),
+            "200 REGISTERED" => Ok(Response::Registered),
+            "200 USERSTATUS UPDATED" => Ok(Response::StatusUpdated),
+            "400 INVALID USERNAME" => Ok(Response::InvalidUsername),
+            "400 INVALID PASSWORD" => Ok(Response::InvalidPassword),
+            "409 ACCOUNT EXISTS" => Ok(Response::AccountExists),
+            "401 INVALID CREDENTIALS" => Ok(Response::InvalidCredentials),
+            "401 LOGIN REQUIRED" => Ok(Response::LoginRequired),
+            "409 ALREADY LOGGED IN" => Ok(Response::AlreadyLoggedIn),
+            "400 ALREADY JOINED" => Ok(Response::AlreadyJoined),
+            "401 NOT JOINED" => Ok(Response::NotJoined),
+            "403 NOT YOUR USER" => Ok(Response::NotYourUser),
+            "400 INVALID ROOM NAME" => Ok(Response::InvalidRoomName),
+            "403 NOT IN ROOM" => Ok(Response::NotInRoom),
+            "404 NO SUCH ROOM" => Ok(Response::NoSuchRoom),
+            "400 MESSAGE FAILED" => Ok(Response::MessageFailed),
+            "400 INVALID MESSAGE FORMAT" => Ok(Response::InvalidMessageFormat),
+            "400 INVALID REQUEST" => Ok(Response::InvalidRequest),
+            "400 LINE TOO LONG" => Ok(Response::LineTooLong),
+            "408 IDLE TIMEOUT" => Ok(Response::IdleTimeout),
+            "200 ADMIN" => Ok(Response::AdminGranted),
+            "403 NOT ADMIN" => Ok(Response::NotAdmin),
+            "404 NO SUCH USER" => Ok(Response::NoSuchUser),
+            "404 NO SUCH MESSAGE" => Ok(Response::NoSuchMessage),
+            "403 NOT YOUR MESSAGE" => Ok(Response::NotYourMessage),
+            "200 KICKED" => Ok(Response::UserKicked),
+            "200 BANNED" => Ok(Response::UserBanned),
+            "200 UNBANNED" => Ok(Response::UserUnbanned),
+            "200 MUTED" => Ok(Response::UserMuted),
+            "200 UNMUTED" => Ok(Response::UserUnmuted),
+            "403 BANNED" => Ok(Response::Banned),
+            "403 MUTED" => Ok(Response::Muted),
+            "500 SERVER ERROR" => Ok(Response::ServerError),
+            _ => Err(ProtocolError::UnexpectedResponse(line.to_string())),
+        }
+    }
+
+    // The wire form of the response, including the trailing newline
+    pub fn encode(&self) -> String {
+        let line = match self {
+            Response::Testing => "100 TESTING".to_string(),
+            Response::Ok => "200 OK".to_string(),
+            Response::Bye => "200 BYE".to_string(),
+            Response::Sent(id) => format!("200 SENT {}", id),
+            Response::Queued(id) => format!("200 QUEUED {}", id),
+            Response::Acked => "200 ACKED".to_string(),
+            Response::MessageEdited => "200 EDITED".to_string(),
+            Response::MessageDeleted => "200 DELETED".to_string(),
+            Response::Registered => "200 REGISTERED".to_string(),
+            Response::Board(board) => format!("200 BOARD {}", encode_json(board)),
+            Response::StatusUpdated => "200 USERSTATUS UPDATED".to_string(),
+            Response::RoomJoined(room) => format!("200 ROOM JOINED {}", room),
+            Response::RoomLeft(room) => format!("200 ROOM LEFT {}", room),
+            Response::Rooms(rooms) => format!("200 ROOMS {}", encode_json(rooms)),
+            Response::History(page) => format!("200 HISTORY {}", encode_json(page)),
+            Response::InvalidUsername => "400 INVALID USERNAME".to_string(),
+            Response::InvalidPassword => "400 INVALID PASSWORD".to_string(),
+            Response::AccountExists => "409 ACCOUNT EXISTS".to_string(),
+            Response::InvalidCredentials => "401 INVALID CREDENTIALS".to_string(),
+            Response::LoginRequired => "401 LOGIN REQUIRED".to_string(),
+            Response::AlreadyLoggedIn => "409 ALREADY LOGGED IN".to_string(),
+            Response::AlreadyJoined => "400 ALREADY JOINED".to_string(),
+            Response::NotJoined => "401 NOT JOINED".to_string(),
+            Response::NotYourUser => "403 NOT YOUR USER".to_string(),
+            Response::InvalidRoomName => "400 INVALID ROOM NAME".to_string(),
+            Response::NotInRoom => "403 NOT IN ROOM".to_string(),
+            Response::NoSuchRoom => "404 NO SUCH ROOM".to_string(),
+            Response::MessageFailed => "400 MESSAGE FAILED".to_string(),
+            Response::InvalidMessageFormat => "400 INVALID MESSAGE FORMAT".to_string(),
+            Response::InvalidRequest => "400 INVALID REQUEST".to_string(),
+            Response::LineTooLong => "400 LINE TOO LONG".to_string(),
+            Response::IdleTimeout => "408 IDLE TIMEOUT".to_string(),
+            Response::AdminGranted => "200 ADMIN".to_string(),
+            Response::NotAdmin => "403 NOT ADMIN".to_string(),
+            Response::NoSuchUser => "404 NO SUCH USER".to_string(),
+            Response::NoSuchMessage => "404 NO SUCH MESSAGE".to_string(),
+            Response::NotYourMessage => "403 NOT YOUR MESSAGE".to_string(),
+            Response::UserKicked => "200 KICKED".to_string(),
+            Response::UserBanned => "200 BANNED".to_string(),
+            Response::UserUnbanned => "200 UNBANNED".to_string(),
+            Response::UserMuted => "200 MUTED".to_string(),
+            Response::UserUnmuted => "200 UNMUTED".to_string(),
+            Response::Banned => "403 BANNED".to_string(),
+            Response::Muted => "403 MUTED".to_string(),
+            Response::ServerError => "500 SERVER ERROR".to_string(),
+            Response::Message(message) => encode_json(message),
+            Response::Presence(event) => format!("PRESENCE {}", encode_json(event)),
+            Response::Receipt(receipt) => format!("RECEIPT {}", encode_json(receipt)),
+            Response::Edited(edit) => format!("EDITED {}", encode_json(edit)),
+            Response::Deleted(id) => format!("DELETED {}", id),
+            Response::Typing(event) => format!("TYPING {}", encode_json(event)),
+            Response::Shutdown(reason) => with_token("SHUTDOWN", reason),
+            Response::Kicked(reason) => with_token("KICKED", reason),
+            Response::Ping(token) => with_token("PING", token),
+            Response::Pong(token) => with_token("PONG", token),
+        };
+        format!("{}\n", line)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    UnknownCommand(String),
+    InvalidArguments(String),
+    InvalidStatus(String),
+    InvalidJson(String),
+    UnexpectedResponse(String),
+}
+
+impl ProtocolError {
+    // The response the server sends back when a command fails to parse
+    pub fn response(&self) -> Response {
+        match self {
+            ProtocolError::UnknownCommand(_) => Response::ServerError,
+            ProtocolError::InvalidJson(_) => Response::InvalidMessageFormat,
+            ProtocolError::InvalidArguments(_)
+            | ProtocolError::InvalidStatus(_)
+            | ProtocolError::UnexpectedResponse(_) => Response::InvalidRequest,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownCommand(command) => write!(f, "unknown command '{}'", command),
+            ProtocolError::InvalidArguments(arguments) => write!(f, "invalid arguments '{}'", arguments),
+            ProtocolError::InvalidStatus(status) => write!(f, "invalid status '{}'", status),
+            ProtocolError::InvalidJson(e) => write!(f, "invalid JSON: {}", e),
+            ProtocolError::UnexpectedResponse(line) => write!(f, "unexpected response '{}'", line),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+// REGISTER and LOGIN take a username and a password; the error never echoes the password
+fn credentials(argument: &str) -> Result<(String, Password), ProtocolError> {
+    match argument.split_whitespace().collect::<Vec<&str>>()[..] {
+        [username, password] => Ok((username.to_string(), Password(password.to_string()))),
+        _ => Err(ProtocolError::InvalidArguments("expected <username> <password>".to_string())),
+    }
+}
+
+fn message_id(id: &str) -> Result<u64, ProtocolError> {
+    id.trim().parse().map_err(|_| ProtocolError::UnexpectedResponse(id.to_string()))
+}
+
+// PING and PONG carry at most one word, which the other side echoes back
+fn token(argument: &str) -> Option<String> {
+    argument.split_whitespace().next().map(str::to_string)
+}
+
+// A blank token or reason is left out, since it would parse back as none at all
+fn with_token(keyword: &str, token: &Option<String>) -> String {
+    match token.as_deref().map(str::trim) {
+        Some(token) if !token.is_empty() => format!("{} {}", keyword, token),
+        _ => keyword.to_string(),
+    }
+}
+
+// The first word names who a moderation command applies to; the rest, if any, is passed back
+fn target_and_rest(argument: &str) -> Result<(String, Option<String>), ProtocolError> {
+    let (target, rest) = argument.split_once(' ').unwrap_or((argument, ""));
+    if target.is_empty() {
+        return Err(ProtocolError::InvalidArguments(argument.to_string()));
+    }
+    let rest = rest.trim();
+    Ok((target.to_string(), (!rest.is_empty()).then(|| rest.to_string())))
+}
+
+// A duration such as 90, 90s, 15m, 2h or 7d, in seconds, followed by optional free text
+fn duration_and_rest(argument: &str) -> Result<(u64, Option<String>), ProtocolError> {
+    let invalid = || ProtocolError::InvalidArguments(argument.to_string());
+    let (duration, rest) = target_and_rest(argument).map_err(|_| invalid())?;
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(position) => duration.split_at(position),
+        None => (duration.as_str(), "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let seconds = number.parse::<u64>().map_err(|_| invalid())?.checked_mul(scale).ok_or_else(invalid)?;
+    if seconds == 0 {
+        return Err(invalid());
+    }
+    Ok((seconds, rest))
+}
+
+fn single_word(argument: &str) -> Result<String, ProtocolError> {
+    match argument.split_whitespace().collect::<Vec<&str>>()[..] {
+        [word] => Ok(word.to_string()),
+        _ => Err(ProtocolError::InvalidArguments(argument.to_string())),
+    }
+}
+
+// JOINROOM and PARTROOM take one room name, with or without the leading '#'
+fn room_argument(argument: &str) -> Result<String, ProtocolError> {
+    let room = argument.trim_start_matches('#');
+    if room.is_empty() || room.contains(char::is_whitespace) {
+        return Err(ProtocolError::InvalidArguments(argument.to_string()));
+    }
+    Ok(room.to_string())
+}
+
+fn encode_json<T: Serialize>(value: &T) -> String {
+    // Only plain structs and string maps go through here, which always serialize
+    serde_json::to_string(value).expect("protocol types always serialize to JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> ChatMessage {
+        ChatMessage {
+            id: Some(7),
+            header: "@bob #general".to_string(),
+            sender: "alice".to_string(),
+            message: "hello there".to_string(),
+            timestamp: Some("12:00".to_string()),
+            sent_at: Some("2024-01-01T12:00:00.000Z".to_string()),
+            edited_at: None,
+        }
+    }
+
+    fn assert_command_round_trip(command: Command) {
+        let line = command.encode();
+        assert!(line.ends_with('\n'), "{:?} encoded without a newline", command);
+        assert_eq!(Command::parse(&line), Ok(command), "line was {:?}", line);
+    }
+
+    fn assert_response_round_trip(response: Response) {
+        let line = response.encode();
+        assert!(line.ends_with('\n'), "{:?} encoded without a newline", response);
+        assert_eq!(Response::parse(&line), Ok(response), "line was {:?}", line);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = vec![
+            Command::Join("alice".to_string()),
+            Command::Register {
+                username: "alice".to_string(),
+                password: Password("hunter22".to_string()),
+            },
+            Command::Login {
+                username: "alice".to_string(),
+                password: Password("hunter22".to_string()),
+            },
+            Command::Leave,
+            Command::Send(message()),
+            Command::UserBoard,
+            Command::UserStatus {
+                username: None,
+                status: Status::DoNotDisturb,
+            },
+            Command::UserStatus {
+                username: Some("bob".to_string()),
+                status: Status::Offline,
+            },
+            Command::JoinRoom("general".to_string()),
+            Command::PartRoom("general".to_string()),
+            Command::Rooms,
+            Command::History {
+                target: "@all".to_string(),
+                count: 20,
+                before: None,
+            },
+            Command::History {
+                target: "#general".to_string(),
+                count: 5,
+                before: Some(40),
+            },
+            Command::Ping(None),
+            Command::Ping(Some("abc".to_string())),
+            Command::Pong(Some("abc".to_string())),
+            Command::Ack {
+                id: 3,
+                state: ReceiptState::Read,
+            },
+            Command::Edit {
+                id: 3,
+                message: "fixed typo".to_string(),
+            },
+            Command::Delete(3),
+            Command::Typing("@bob @carol".to_string()),
+            Command::Admin(Password("secret".to_string())),
+            Command::Kick {
+                username: "bob".to_string(),
+                reason: None,
+            },
+            Command::Kick {
+                username: "bob".to_string(),
+                reason: Some("too loud".to_string()),
+            },
+            Command::Ban {
+                target: "192.0.2.1".to_string(),
+                seconds: 900,
+                reason: Some("spam".to_string()),
+            },
+            Command::Unban("bob".to_string()),
+            Command::Mute {
+                username: "bob".to_string(),
+                seconds: 60,
+            },
+            Command::Unmute("bob".to_string()),
+        ];
+        for command in commands {
+            assert_command_round_trip(command);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let responses = vec![
+            Response::Testing,
+            Response::Ok,
+            Response::Bye,
+            Response::Sent(1),
+            Response::Registered,
+            Response::Queued(2),
+            Response::Acked,
+            Response::MessageEdited,
+            Response::MessageDeleted,
+            Response::Board(BTreeMap::from([
+                ("alice".to_string(), Status::Online),
+                ("bob".to_string(), Status::DoNotDisturb),
+            ])),
+            Response::StatusUpdated,
+            Response::RoomJoined("general".to_string()),
+            Response::RoomLeft("general".to_string()),
+            Response::Rooms(BTreeMap::from([("general".to_string(), vec!["alice".to_string()])])),
+            Response::History(HistoryPage {
+                target: "@all".to_string(),
+                messages: vec![HistoryEntry {
+                    id: 7,
+                    message: ChatMessage { id: None, ..message() },
+                }],
+            }),
+            Response::InvalidUsername,
+            Response::InvalidPassword,
+            Response::AccountExists,
+            Response::InvalidCredentials,
+            Response::LoginRequired,
+            Response::AlreadyLoggedIn,
+            Response::AlreadyJoined,
+            Response::NotJoined,
+            Response::NotYourUser,
+            Response::InvalidRoomName,
+            Response::NotInRoom,
+            Response::NoSuchRoom,
+            Response::MessageFailed,
+            Response::InvalidMessageFormat,
+            Response::InvalidRequest,
+            Response::LineTooLong,
+            Response::IdleTimeout,
+            Response::AdminGranted,
+            Response::NotAdmin,
+            Response::NoSuchUser,
+            Response::NoSuchMessage,
+            Response::NotYourMessage,
+            Response::UserKicked,
+            Response::UserBanned,
+            Response::UserUnbanned,
+            Response::UserMuted,
+            Response::UserUnmuted,
+            Response::Banned,
+            Response::Muted,
+            Response::ServerError,
+            Response::Message(message()),
+            Response::Presence(PresenceEvent {
+                event: PresenceKind::Status,
+                username: "alice".to_string(),
+                status: Status::DoNotDisturb,
+            }),
+            Response::Receipt(Receipt {
+                id: 7,
+                username: "bob".to_string(),
+                state: ReceiptState::Delivered,
+            }),
+            Response::Edited(MessageEdit {
+                id: 7,
+                message: "hello again".to_string(),
+                edited_at: "2024-01-01T12:01:00.000Z".to_string(),
+            }),
+            Response::Deleted(7),
+            Response::Typing(TypingEvent {
+                username: "alice".to_string(),
+                header: "@all".to_string(),
+                expires_in: 5,
+            }),
+            Response::Shutdown(None),
+            Response::Shutdown(Some("restarting for an upgrade".to_string())),
+            Response::Kicked(None),
+            Response::Kicked(Some("be nice".to_string())),
+            Response::Ping(None),
+            Response::Ping(Some("abc".to_string())),
+            Response::Pong(Some("abc".to_string())),
+        ];
+        for response in responses {
+            assert_response_round_trip(response);
+        }
+    }
+
+    #[test]
+    fn blank_reasons_encode_as_none() {
+        assert_eq!(Response::Shutdown(Some(String::new())).encode(), "SHUTDOWN\n");
+        assert_eq!(Response::Kicked(Some("  ".to_string())).encode(), "KICKED\n");
+        assert_eq!(Response::parse("SHUTDOWN \n"), Ok(Response::Shutdown(None)));
+    }
+
+    #[test]
+    fn parse_errors_map_to_responses() {
+        let unknown = Command::parse("FROBNICATE now").unwrap_err();
+        assert_eq!(unknown, ProtocolError::UnknownCommand("FROBNICATE".to_string()));
+        assert_eq!(unknown.response(), Response::ServerError);
+
+        let bad_json = Command::parse("SEND {not json").unwrap_err();
+        assert_eq!(bad_json.response(), Response::InvalidMessageFormat);
+
+        let bad_status = Command::parse("USERSTATUS SLEEPING").unwrap_err();
+        assert_eq!(bad_status.response(), Response::InvalidRequest);
+    }
+
+    #[test]
+    fn durations_take_units_and_reject_overflow() {
+        assert_eq!(duration_and_rest("90"), Ok((90, None)));
+        assert_eq!(duration_and_rest("15m spamming"), Ok((900, Some("spamming".to_string()))));
+        assert_eq!(duration_and_rest("2h"), Ok((7200, None)));
+        assert_eq!(duration_and_rest("7d"), Ok((604_800, None)));
+        assert!(duration_and_rest("0").is_err());
+        assert!(duration_and_rest("5w").is_err());
+        assert!(duration_and_rest("").is_err());
+        assert!(duration_and_rest("18446744073709551615d").is_err());
+        // Fits in a u64, so parsing accepts it; the server refuses it when working out the expiry
+        assert_eq!(duration_and_rest("18446744073709551615"), Ok((u64::MAX, None)));
+    }
+
+    #[test]
+    fn header_targets() {
+        let message = message();
+        assert!(!message.is_broadcast());
+        assert_eq!(message.recipients(), vec!["bob"]);
+        assert_eq!(message.rooms(), vec!["general"]);
+
+        let broadcast = ChatMessage {
+            header: " @all ".to_string(),
+            ..message
+        };
+        assert!(broadcast.is_broadcast());
+    }
+}